@@ -0,0 +1,134 @@
+#!/usr/bin/env python3
+"""Manage the per-device keys for encrypted values in securedash.toml.
+
+Generate a key for a device and flash it into its NVS partition:
+
+    python scripts/secret.py keygen 7cdfa1e2f3a4
+
+Flashing replaces the whole NVS partition, so the path overrides to keep have to be passed along:
+
+    python scripts/secret.py keygen 7cdfa1e2f3a4 --set mount_path=/sdcard
+
+Encrypt a value for the device, and paste the output into securedash.toml:
+
+    python scripts/secret.py encrypt 7cdfa1e2f3a4 "my-super-duper-secret-password"
+
+Print the passphrase of the device's setup access point:
+
+    python scripts/secret.py ap-password 7cdfa1e2f3a4
+
+Requires the `cryptography` package.
+"""
+import argparse
+import base64
+import hashlib
+import os
+import pathlib
+import sys
+
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+
+KEYS_DIR = pathlib.Path("keys")
+NVS_NAMESPACE = "securedash"
+NVS_KEY = "secret_key"
+NVS_PARTITION_OFFSET = "0x9000"
+NVS_PARTITION_SIZE = "0x6000"
+NONCE_SIZE = 12
+# Has to match the ones in src/provisioning/portal.rs
+AP_PASSPHRASE_SALT = "securedash-setup:"
+AP_PASSPHRASE_LEN = 16
+
+
+def key_path(device_id: str) -> pathlib.Path:
+    return KEYS_DIR / f"{device_id}.key"
+
+
+def nvs_entry(entry: str) -> tuple[str, str]:
+    name, sep, value = entry.partition("=")
+    if not sep or not name:
+        raise argparse.ArgumentTypeError(f"{entry!r} should look like key=value")
+    return name, value
+
+
+def keygen(args: argparse.Namespace):
+    path = key_path(args.device_id)
+    if path.exists() and not args.force:
+        sys.exit(f"Key {path} already exists, use --force to replace it")
+    KEYS_DIR.mkdir(exist_ok=True)
+    key = AESGCM.generate_key(bit_length=256)
+    path.write_text(key.hex())
+    csv_path = KEYS_DIR / f"{args.device_id}-nvs.csv"
+    csv_path.write_text(
+        "key,type,encoding,value\n"
+        f"{NVS_NAMESPACE},namespace,,\n"
+        f"{NVS_KEY},data,hex2bin,{key.hex()}\n"
+        + "".join(f"{name},data,string,{value}\n" for name, value in args.set)
+    )
+    bin_path = KEYS_DIR / f"{args.device_id}-nvs.bin"
+    print(f"Key written to {path}, keep it safe and out of the repository.")
+    print("Flash it into the device NVS partition with:")
+    print(
+        "  python $IDF_PATH/components/nvs_flash/nvs_partition_generator/nvs_partition_gen.py "
+        f"generate {csv_path} {bin_path} {NVS_PARTITION_SIZE}"
+    )
+    print(f"  esptool.py --chip esp32s3 write_flash {NVS_PARTITION_OFFSET} {bin_path}")
+    print("This erases everything else in NVS:")
+    print("  - the Wifi driver data, such as the RF calibration, which is regenerated on boot")
+    print("  - the last known-good config, which is stored again once the device connects to Wifi")
+    print("  - the events not delivered to the server yet")
+    print("  - the path overrides, unless they are passed with --set")
+
+
+def encrypt(args: argparse.Namespace):
+    path = key_path(args.device_id)
+    if not path.exists():
+        sys.exit(f"No key found at {path}, generate one with the keygen command first")
+    key = bytes.fromhex(path.read_text().strip())
+    nonce = os.urandom(NONCE_SIZE)
+    # AESGCM appends the 16 bytes tag to the ciphertext
+    ciphertext = AESGCM(key).encrypt(nonce, args.value.encode("utf8"), None)
+    encoded = base64.b64encode(nonce + ciphertext).decode("ascii")
+    print(f'{{ encrypted = "{encoded}" }}')
+
+
+def ap_password(args: argparse.Namespace):
+    digest = hashlib.sha256(f"{AP_PASSPHRASE_SALT}{args.device_id}".encode("utf8")).hexdigest()
+    print(digest[:AP_PASSPHRASE_LEN])
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter
+    )
+    subparsers = parser.add_subparsers(required=True)
+
+    keygen_parser = subparsers.add_parser("keygen", help="generate a key for a device")
+    keygen_parser.add_argument("device_id")
+    keygen_parser.add_argument("--force", action="store_true", help="replace the existing key")
+    keygen_parser.add_argument(
+        "--set",
+        type=nvs_entry,
+        action="append",
+        default=[],
+        metavar="KEY=VALUE",
+        help="also store a string, like a path override, as flashing erases the rest of NVS",
+    )
+    keygen_parser.set_defaults(func=keygen)
+
+    encrypt_parser = subparsers.add_parser("encrypt", help="encrypt a value for a device")
+    encrypt_parser.add_argument("device_id")
+    encrypt_parser.add_argument("value")
+    encrypt_parser.set_defaults(func=encrypt)
+
+    ap_password_parser = subparsers.add_parser(
+        "ap-password", help="print the passphrase of the setup access point of a device"
+    )
+    ap_password_parser.add_argument("device_id")
+    ap_password_parser.set_defaults(func=ap_password)
+
+    args = parser.parse_args()
+    args.func(args)
+
+
+if __name__ == "__main__":
+    main()
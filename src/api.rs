@@ -1,2 +1,6 @@
+pub mod http;
+pub mod mqtt;
+pub mod outbox;
 pub mod processor;
+pub mod transport;
 pub mod websocket;
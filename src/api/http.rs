@@ -0,0 +1,294 @@
+use crate::api::transport::{
+    ChannelReceiver, ConnectionState, DesiredState, SessionChannel, SessionEvent, Transport,
+};
+use crate::config::Endpoint;
+use crate::encoding::base64_encode;
+use anyhow::{anyhow, bail};
+use core::time;
+use embedded_svc::http::client::Client;
+use embedded_svc::http::Method;
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::hal::task::block_on;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::sys::esp_crt_bundle_attach;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+/// How long the server may hold a long-poll request before replying with no command
+const LONG_POLL_TIMEOUT: time::Duration = time::Duration::from_secs(25);
+/// Delay before reporting a failed poll, so that we don't hammer the server while it's down
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(10);
+/// The TLS handshake with mbedTLS takes most of it for https endpoints
+const POLL_THREAD_STACK_SIZE: usize = 20 * 1024;
+const READ_BUFFER_SIZE: usize = 1024;
+
+struct SessionState {
+    desired_state: DesiredState,
+    connection_state: ConnectionState,
+    // Bumped on every connect, so that a poll thread left over from a previous connection
+    // can tell it's stale and stop touching the state
+    generation: u64,
+    channel: Arc<SessionChannel>,
+}
+
+impl SessionState {
+    fn set_state(&mut self, new_state: ConnectionState) {
+        let old_state = self.connection_state;
+        if old_state == new_state {
+            return;
+        }
+        self.connection_state = new_state;
+        let channel = self.channel.clone();
+        block_on(async {
+            channel
+                .sender()
+                .send(SessionEvent::StateChange {
+                    old_state,
+                    new_state,
+                })
+                .await;
+        });
+    }
+}
+
+/// Carries the same command protocol as the WebSocket session over plain HTTP(S) for networks
+/// stripping WebSocket upgrades. Commands are fetched by long-polling `GET <url>/commands` and
+/// responses are delivered with `POST <url>/responses`.
+pub struct HttpSession {
+    endpoints: Vec<Endpoint>,
+    endpoint_index: usize,
+    timeout: time::Duration,
+    post_client: Option<Client<EspHttpConnection>>,
+    state: Arc<RwLock<SessionState>>,
+}
+
+fn authorization(endpoint: &Endpoint) -> anyhow::Result<Option<String>> {
+    if let Some(token) = &endpoint.token {
+        return Ok(Some(format!("Bearer {token}")));
+    }
+    if let Some(username) = &endpoint.username {
+        let password = endpoint.password.as_deref().unwrap_or("");
+        return Ok(Some(format!(
+            "Basic {}",
+            base64_encode(format!("{username}:{password}").as_bytes())?
+        )));
+    }
+    Ok(None)
+}
+
+fn new_client(timeout: time::Duration) -> anyhow::Result<Client<EspHttpConnection>> {
+    Ok(Client::wrap(EspHttpConnection::new(&Configuration {
+        timeout: Some(timeout),
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?))
+}
+
+fn poll_commands(
+    url: String,
+    authorization: Option<String>,
+    timeout: time::Duration,
+    generation: u64,
+    state: Arc<RwLock<SessionState>>,
+) -> anyhow::Result<()> {
+    let is_current = || {
+        let state = state.read().unwrap();
+        state.generation == generation && matches!(state.desired_state, DesiredState::Connected)
+    };
+    let mut client = new_client(LONG_POLL_TIMEOUT + timeout)?;
+    let url = format!("{url}/commands?timeout={}", LONG_POLL_TIMEOUT.as_secs());
+    let mut headers: Vec<(&str, &str)> = vec![];
+    if let Some(authorization) = &authorization {
+        headers.push(("Authorization", authorization));
+    }
+    let mut buf = [0u8; READ_BUFFER_SIZE];
+    while is_current() {
+        let mut response = client.request(Method::Get, &url, &headers)?.submit()?;
+        let status = response.status();
+        if !is_current() {
+            break;
+        }
+        match status {
+            200 | 204 => {
+                state.write().unwrap().set_state(ConnectionState::Connected);
+            }
+            _ => bail!("Unexpected status {status} from {url}"),
+        }
+        if status == 204 {
+            continue;
+        }
+        let mut body: Vec<u8> = vec![];
+        loop {
+            let read_size = response.read(&mut buf)?;
+            if read_size == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..read_size]);
+        }
+        let text = String::from_utf8(body)?;
+        log::debug!("Http recv, text: {text}");
+        let channel = state.read().unwrap().channel.clone();
+        block_on(async {
+            channel
+                .sender()
+                .send(SessionEvent::ReceiveText { text })
+                .await;
+        });
+    }
+    Ok(())
+}
+
+impl HttpSession {
+    pub fn new(endpoints: &[Endpoint], timeout: time::Duration) -> Self {
+        Self {
+            endpoints: endpoints.to_vec(),
+            endpoint_index: 0,
+            timeout,
+            post_client: None,
+            state: Arc::new(RwLock::new(SessionState {
+                desired_state: DesiredState::Disconnected,
+                connection_state: ConnectionState::Disconnected,
+                generation: 0,
+                channel: Arc::new(SessionChannel::new()),
+            })),
+        }
+    }
+}
+
+impl Transport for HttpSession {
+    fn get_desired_state(&self) -> DesiredState {
+        self.state.read().unwrap().desired_state
+    }
+
+    fn get_connection_state(&self) -> ConnectionState {
+        self.state.read().unwrap().connection_state
+    }
+
+    fn active_endpoint(&self) -> Option<&Endpoint> {
+        self.endpoints.get(self.endpoint_index)
+    }
+
+    fn endpoint_count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    fn endpoint_index(&self) -> usize {
+        self.endpoint_index
+    }
+
+    fn connect(&mut self) -> anyhow::Result<()> {
+        let endpoint = self
+            .endpoints
+            .get(self.endpoint_index)
+            .ok_or_else(|| anyhow!("No endpoint configured"))?;
+        let generation = {
+            let mut state = self.state.write().unwrap();
+            if state.connection_state != ConnectionState::Disconnected {
+                let conn_state = &state.connection_state;
+                log::info!("Already in {conn_state:?} state, do nothing");
+                bail!("Already connected");
+            }
+            state.desired_state = DesiredState::Connected;
+            state.generation += 1;
+            state.set_state(ConnectionState::Connecting);
+            state.generation
+        };
+
+        let url = endpoint.url.trim_end_matches('/').to_string();
+        let authorization = authorization(endpoint)?;
+        let timeout = self.timeout;
+        let state = self.state.clone();
+        thread::Builder::new()
+            .stack_size(POLL_THREAD_STACK_SIZE)
+            .spawn(move || {
+                let result = poll_commands(url, authorization, timeout, generation, state.clone());
+                if let Err(error) = result {
+                    log::warn!("Http long-poll failed with error: {error:#}");
+                    thread::sleep(RECONNECT_DELAY);
+                }
+                let mut state = state.write().unwrap();
+                if state.generation == generation {
+                    state.set_state(ConnectionState::Disconnected);
+                }
+            })?;
+        log::info!(
+            "Change desired state to Connected, endpoint={}, index={}",
+            endpoint.url,
+            self.endpoint_index
+        );
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        let mut state = self.state.write().unwrap();
+        self.post_client = None;
+        state.desired_state = DesiredState::Disconnected;
+        // Invalidate the running poll thread, it will exit once the pending request returns
+        state.generation += 1;
+        state.connection_state = ConnectionState::Disconnected;
+        log::info!("Change desired state to Disconnected")
+    }
+
+    fn rotate_endpoint(&mut self) -> anyhow::Result<()> {
+        if self.endpoints.is_empty() {
+            bail!("No endpoint configured");
+        }
+        self.disconnect();
+        self.endpoint_index = (self.endpoint_index + 1) % self.endpoints.len();
+        self.connect()
+    }
+
+    fn acquire_receiver(&mut self) -> ChannelReceiver {
+        ChannelReceiver::new(self.state.read().unwrap().channel.clone())
+    }
+
+    fn send(
+        &mut self,
+        _request_id: Option<&str>,
+        frame_type: FrameType,
+        frame_data: &[u8],
+    ) -> anyhow::Result<()> {
+        if self.get_connection_state() != ConnectionState::Connected {
+            bail!("Not connected yet");
+        }
+        let endpoint = self
+            .endpoints
+            .get(self.endpoint_index)
+            .ok_or_else(|| anyhow!("No endpoint configured"))?;
+        let url = format!("{}/responses", endpoint.url.trim_end_matches('/'));
+        let authorization = authorization(endpoint)?;
+        let content_type = match frame_type {
+            FrameType::Binary(_) => "application/msgpack",
+            _ => "application/json",
+        };
+        let content_length = frame_data.len().to_string();
+        let mut headers: Vec<(&str, &str)> = vec![
+            ("Content-Type", content_type),
+            ("Content-Length", &content_length),
+        ];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+
+        if self.post_client.is_none() {
+            self.post_client = Some(new_client(self.timeout)?);
+        }
+        let client = self.post_client.as_mut().unwrap();
+        let result = (|| -> anyhow::Result<()> {
+            let mut request = client.post(&url, &headers)?;
+            request.write_all(frame_data)?;
+            request.flush()?;
+            let status = request.submit()?.status();
+            if !(200..300).contains(&status) {
+                bail!("Unexpected status {status} from {url}");
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            // The connection may be in a broken state, start over with a new one next time
+            self.post_client = None;
+        }
+        result
+    }
+}
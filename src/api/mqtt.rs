@@ -0,0 +1,247 @@
+use crate::api::transport::{
+    ChannelReceiver, ConnectionState, DesiredState, SessionChannel, SessionEvent, Transport,
+};
+use crate::config::Endpoint;
+use anyhow::{anyhow, bail};
+use core::time;
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::hal::task::block_on;
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, EspMqttEvent, EventPayload, MqttClientConfiguration, QoS,
+};
+use std::sync::{Arc, RwLock};
+
+const TOPIC_PREFIX: &str = "securedash";
+/// esp-mqtt reconnects to the same broker on its own, so a disconnection is only reported to
+/// rotate the endpoint after this many attempts in a row have failed
+const MAX_FAILED_RECONNECTS: usize = 3;
+
+struct SessionState {
+    desired_state: DesiredState,
+    connection_state: ConnectionState,
+    cmd_topic: String,
+    channel: Arc<SessionChannel>,
+    failed_reconnects: usize,
+}
+
+impl SessionState {
+    fn set_state(&mut self, new_state: ConnectionState) {
+        let old_state = self.connection_state;
+        self.connection_state = new_state;
+        let channel = self.channel.clone();
+        block_on(async {
+            channel
+                .sender()
+                .send(SessionEvent::StateChange {
+                    old_state,
+                    new_state,
+                })
+                .await;
+        });
+    }
+
+    fn handle_event(&mut self, event: EspMqttEvent<'_>) {
+        match event.payload() {
+            EventPayload::BeforeConnect => {
+                log::info!("MQTT before connect");
+                self.set_state(ConnectionState::BeforeConnect);
+            }
+            EventPayload::Connected(_) => {
+                log::info!("MQTT connected");
+                self.failed_reconnects = 0;
+                self.set_state(ConnectionState::Connected);
+            }
+            EventPayload::Disconnected => {
+                self.failed_reconnects += 1;
+                if self.failed_reconnects < MAX_FAILED_RECONNECTS {
+                    log::info!(
+                        "MQTT disconnected, reconnect attempt {}/{MAX_FAILED_RECONNECTS}",
+                        self.failed_reconnects
+                    );
+                    self.connection_state = ConnectionState::Connecting;
+                    return;
+                }
+                log::info!("MQTT disconnected");
+                self.set_state(ConnectionState::Disconnected);
+            }
+            EventPayload::Received {
+                topic,
+                data,
+                details,
+                ..
+            } => {
+                if topic != Some(self.cmd_topic.as_str()) {
+                    log::debug!("MQTT recv on unexpected topic {topic:?}, ignored");
+                    return;
+                }
+                if !matches!(details, Details::Complete) {
+                    log::warn!("MQTT recv fragmented command, ignored");
+                    return;
+                }
+                let text = String::from_utf8_lossy(data).to_string();
+                log::debug!("MQTT recv, text: {text}");
+                let channel = self.channel.clone();
+                block_on(async {
+                    channel
+                        .sender()
+                        .send(SessionEvent::ReceiveText { text })
+                        .await;
+                });
+            }
+            EventPayload::Error(error) => {
+                log::warn!("MQTT error: {error:?}");
+            }
+            payload => {
+                log::debug!("MQTT event: {payload:?}");
+            }
+        }
+    }
+}
+
+/// Receives commands from `securedash/<device-id>/cmd` on a MQTT broker, and publishes responses
+/// to `securedash/<device-id>/resp/<request-id>`.
+pub struct MqttSession<'a> {
+    endpoints: Vec<Endpoint>,
+    endpoint_index: usize,
+    timeout: time::Duration,
+    device_id: String,
+    mqtt_client: Option<EspMqttClient<'a>>,
+    state: Arc<RwLock<SessionState>>,
+}
+
+impl<'a> MqttSession<'a> {
+    pub fn new(endpoints: &[Endpoint], timeout: time::Duration, device_id: &str) -> Self {
+        Self {
+            endpoints: endpoints.to_vec(),
+            endpoint_index: 0,
+            timeout,
+            device_id: device_id.to_string(),
+            mqtt_client: None,
+            state: Arc::new(RwLock::new(SessionState {
+                desired_state: DesiredState::Disconnected,
+                connection_state: ConnectionState::Disconnected,
+                cmd_topic: format!("{TOPIC_PREFIX}/{device_id}/cmd"),
+                channel: Arc::new(SessionChannel::new()),
+                failed_reconnects: 0,
+            })),
+        }
+    }
+
+    fn topic(&self, request_id: Option<&str>) -> String {
+        match request_id {
+            Some(request_id) => format!("{TOPIC_PREFIX}/{}/resp/{request_id}", self.device_id),
+            None => format!("{TOPIC_PREFIX}/{}/event", self.device_id),
+        }
+    }
+}
+
+impl Transport for MqttSession<'_> {
+    fn get_desired_state(&self) -> DesiredState {
+        self.state.read().unwrap().desired_state
+    }
+
+    fn get_connection_state(&self) -> ConnectionState {
+        self.state.read().unwrap().connection_state
+    }
+
+    fn active_endpoint(&self) -> Option<&Endpoint> {
+        self.endpoints.get(self.endpoint_index)
+    }
+
+    fn endpoint_count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    fn endpoint_index(&self) -> usize {
+        self.endpoint_index
+    }
+
+    fn connect(&mut self) -> anyhow::Result<()> {
+        let mut write_lock = self.state.write();
+        let state = write_lock.as_mut().unwrap();
+        if state.connection_state != ConnectionState::Disconnected {
+            let conn_state = &state.connection_state;
+            log::info!("Already in {conn_state:?} state, do nothing");
+            bail!("Already connected");
+        }
+        let endpoint = self
+            .endpoints
+            .get(self.endpoint_index)
+            .ok_or_else(|| anyhow!("No endpoint configured"))?;
+        state.desired_state = DesiredState::Connected;
+        state.failed_reconnects = 0;
+        let config = MqttClientConfiguration {
+            client_id: Some(self.device_id.as_str()),
+            username: endpoint.username.as_deref(),
+            password: endpoint.password.as_deref(),
+            network_timeout: self.timeout,
+            ..Default::default()
+        };
+        let weak_state = Arc::downgrade(&self.state);
+        self.mqtt_client = Some(EspMqttClient::new_cb(
+            &endpoint.url,
+            &config,
+            move |event| {
+                if let Some(state) = weak_state.upgrade() {
+                    state.write().unwrap().handle_event(event);
+                }
+            },
+        )?);
+        log::info!(
+            "Change desired state to Connected, endpoint={}, index={}",
+            endpoint.url,
+            self.endpoint_index
+        );
+        Ok(())
+    }
+
+    fn on_connected(&mut self) -> anyhow::Result<()> {
+        let cmd_topic = self.state.read().unwrap().cmd_topic.clone();
+        let mqtt_client = self
+            .mqtt_client
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not connected yet"))?;
+        mqtt_client.subscribe(&cmd_topic, QoS::AtLeastOnce)?;
+        log::info!("Subscribed to {cmd_topic}");
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        // Drop the client before taking the lock, as it waits for the MQTT task which may be
+        // blocked on the lock in the event callback
+        self.mqtt_client = None;
+        let mut write_lock = self.state.write();
+        let state = write_lock.as_mut().unwrap();
+        state.desired_state = DesiredState::Disconnected;
+        state.connection_state = ConnectionState::Disconnected;
+        log::info!("Change desired state to Disconnected")
+    }
+
+    fn rotate_endpoint(&mut self) -> anyhow::Result<()> {
+        if self.endpoints.is_empty() {
+            bail!("No endpoint configured");
+        }
+        self.disconnect();
+        self.endpoint_index = (self.endpoint_index + 1) % self.endpoints.len();
+        self.connect()
+    }
+
+    fn acquire_receiver(&mut self) -> ChannelReceiver {
+        ChannelReceiver::new(self.state.read().unwrap().channel.clone())
+    }
+
+    fn send(
+        &mut self,
+        request_id: Option<&str>,
+        _frame_type: FrameType,
+        frame_data: &[u8],
+    ) -> anyhow::Result<()> {
+        if self.get_connection_state() != ConnectionState::Connected {
+            bail!("Not connected yet");
+        }
+        let topic = self.topic(request_id);
+        let mqtt_client = self.mqtt_client.as_mut().unwrap();
+        mqtt_client.publish(&topic, QoS::AtLeastOnce, false, frame_data)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use time::serde::timestamp::milliseconds;
+use time::OffsetDateTime;
+
+const NVS_NAMESPACE: &str = "securedash";
+const NVS_KEY: &str = "outbox";
+/// The NVS partition is shared with the Wi-Fi driver and the config store, so the oldest events
+/// are dropped to keep the stored outbox within this size
+const MAX_OUTBOX_SIZE: usize = 4 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Event {
+    NewSentryClip {
+        path: String,
+    },
+    LowSpace {
+        total_volume_size: u64,
+        free_volume_size: u64,
+    },
+    UsbMountChanged {
+        is_mounted: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EventRecord {
+    pub seq: u64,
+    #[serde(with = "milliseconds")]
+    pub created_at: OffsetDateTime,
+    pub event: Event,
+}
+
+/// What's kept in NVS
+#[derive(Serialize, Deserialize, Default)]
+struct Stored {
+    next_seq: u64,
+    events: VecDeque<EventRecord>,
+}
+
+/// A bounded queue of events kept in memory and persisted to NVS, so that events happening while
+/// there's no connection survive until we can deliver them (or a reboot). It doesn't touch the
+/// volume, which the USB host may own at any time.
+pub struct Outbox {
+    nvs: EspNvs<NvsDefault>,
+    max_events: usize,
+    next_seq: u64,
+    events: VecDeque<EventRecord>,
+    notifier: Rc<Signal<CriticalSectionRawMutex, ()>>,
+}
+
+impl Outbox {
+    pub fn open(partition: EspDefaultNvsPartition, max_events: usize) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let stored = load(&nvs).unwrap_or_else(|error| {
+            log::warn!("Dropping stored outbox with error: {error:#}");
+            Stored::default()
+        });
+        let outbox = Self {
+            nvs,
+            max_events,
+            next_seq: stored.next_seq.max(1),
+            events: stored.events,
+            notifier: Rc::new(Signal::new()),
+        };
+        log::info!(
+            "Opened outbox with {} pending events, next_seq={}",
+            outbox.events.len(),
+            outbox.next_seq
+        );
+        Ok(outbox)
+    }
+
+    pub fn notifier(&self) -> Rc<Signal<CriticalSectionRawMutex, ()>> {
+        self.notifier.clone()
+    }
+
+    pub fn push(&mut self, event: Event) -> anyhow::Result<u64> {
+        let record = EventRecord {
+            seq: self.next_seq,
+            created_at: OffsetDateTime::now_utc(),
+            event,
+        };
+        self.next_seq += 1;
+        log::info!("Queued event {record:?}");
+        let seq = record.seq;
+        self.events.push_back(record);
+        if self.events.len() > self.max_events {
+            let dropping = self.events.len() - self.max_events;
+            log::warn!("Outbox is full, dropping {dropping} oldest events");
+            self.events.drain(..dropping);
+        }
+        // Even if it fails to persist, the event is still delivered unless we reboot first
+        let result = self.save();
+        self.notifier.signal(());
+        result.map(|_| seq)
+    }
+
+    /// Send all pending events in order. It stops at the first failed one so that the order is
+    /// preserved for the next attempt.
+    pub fn flush<F>(&mut self, mut send: F) -> anyhow::Result<usize>
+    where
+        F: FnMut(&EventRecord) -> anyhow::Result<()>,
+    {
+        let mut count: usize = 0;
+        let mut result = Ok(());
+        while let Some(record) = self.events.front() {
+            if let Err(error) = send(record) {
+                result = Err(error);
+                break;
+            }
+            self.events.pop_front();
+            count += 1;
+        }
+        if count > 0 {
+            log::info!("Flushed {count} events from outbox");
+            self.save()?;
+        }
+        result.map(|_| count)
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        let mut stored = Stored {
+            next_seq: self.next_seq,
+            events: self.events.clone(),
+        };
+        let mut content = serde_json::to_vec(&stored)?;
+        while content.len() > MAX_OUTBOX_SIZE && !stored.events.is_empty() {
+            stored.events.pop_front();
+            content = serde_json::to_vec(&stored)?;
+        }
+        let dropped = self.events.len() - stored.events.len();
+        if dropped > 0 {
+            log::warn!("Outbox is too large to store, only the latest events are kept");
+            self.events.drain(..dropped);
+        }
+        self.nvs.set_blob(NVS_KEY, &content)?;
+        Ok(())
+    }
+}
+
+fn load(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<Stored> {
+    let Some(len) = nvs.blob_len(NVS_KEY)? else {
+        return Ok(Stored::default());
+    };
+    let mut buf = vec![0u8; len];
+    let content = nvs.get_blob(NVS_KEY, &mut buf)?.unwrap_or_default();
+    Ok(serde_json::from_slice(content)?)
+}
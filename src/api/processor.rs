@@ -1,21 +1,56 @@
-use crate::api::processor::Response::{Error, FetchFileChunk, GetInfo, ListFiles, Reboot};
-use crate::api::websocket::{ConnectionState, SessionEvent, WebSocketSession};
-use anyhow::anyhow;
+use crate::api::outbox::Outbox;
+use crate::api::processor::Response::{
+    Error, FetchFileChunk, GetConfig, GetInfo, ListFiles, Reboot, ScanWifi, UpdateConfig,
+};
+use crate::api::transport::{ConnectionState, DesiredState, SessionEvent, Transport};
+use crate::config::editor::{ConfigEditor, ConfigPatch};
+use crate::config::ConfigSource;
+use crate::wifi::link::link_quality;
+use crate::wifi::status::{SharedWifiStatus, WifiState, WifiStateSignal};
+use anyhow::{anyhow, bail, Context};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
 use embedded_svc::ws::FrameType;
+use esp_idf_svc::timer::EspTaskTimerService;
+use futures::future::{pending, select, Either, LocalBoxFuture};
 use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::fs::read_dir;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::pin::pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use time::serde::timestamp::milliseconds;
 use time::OffsetDateTime;
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkSizeMode {
+    Auto,
+}
+
+/// Either a fixed chunk size in bytes, or `"auto"` to let the device pick one from the link
+/// quality and the measured throughput
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum ChunkSize {
+    Fixed(u64),
+    Mode(ChunkSizeMode),
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum Command {
     GetInfo,
     ListFiles { path: String },
-    FetchFile { path: String, chunk_size: u64 },
+    FetchFile { path: String, chunk_size: ChunkSize },
     Reboot,
+    ScanWifi,
+    GetConfig,
+    UpdateConfig { patch: ConfigPatch },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -33,11 +68,34 @@ pub struct File {
 pub struct DeviceInfo {
     pub version: String,
     pub wifi_ip: String,
+    pub wifi_ssid: Option<String>,
+    pub wifi_gateway: Option<String>,
+    pub wifi_netmask: Option<String>,
+    pub wifi_dns: Vec<String>,
+    pub wifi_hostname: Option<String>,
+    pub wifi_rssi: Option<i8>,
+    pub wifi_channel: Option<u8>,
+    pub wifi_phy_mode: Option<String>,
+    pub partition_label: String,
     pub mount_path: String,
+    pub config_path: String,
+    /// `low`, `full` or `high`, `None` when the USB host isn't connected
+    pub usb_speed: Option<String>,
     #[serde(with = "milliseconds")]
     pub local_time: OffsetDateTime,
     pub total_volume_size: u64,
     pub free_volume_size: u64,
+    pub api_endpoint: Option<String>,
+    pub config_source: ConfigSource,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WifiNetworkInfo {
+    pub ssid: String,
+    pub bssid: String,
+    pub channel: u8,
+    pub rssi: i8,
+    pub auth_method: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -56,6 +114,16 @@ pub enum Response<'a> {
         is_final: bool,
     },
     Reboot,
+    ScanWifi {
+        networks: Vec<WifiNetworkInfo>,
+    },
+    GetConfig {
+        config: serde_json::Value,
+    },
+    UpdateConfig {
+        config: serde_json::Value,
+        warnings: Vec<String>,
+    },
     Error {
         message: String,
     },
@@ -73,16 +141,82 @@ pub struct CommandResponse<'a> {
     pub response: Response<'a>,
 }
 
-pub type DeviceInfoProducer = Box<dyn Fn() -> anyhow::Result<DeviceInfo>>;
+pub type DeviceInfoProducer = Box<dyn Fn() -> anyhow::Result<DeviceInfo> + Send + Sync>;
+/// Scans for the Wi-Fi networks in range without dropping the current connection
+pub type WifiScanner =
+    Arc<dyn Fn() -> LocalBoxFuture<'static, anyhow::Result<Vec<WifiNetworkInfo>>> + Send + Sync>;
+pub type Session<'a> = (Box<dyn Transport + 'a>, DeviceInfoProducer);
+pub type SessionFactory<'a> = Box<dyn FnMut() -> LocalBoxFuture<'a, anyhow::Result<Session<'a>>>>;
+/// Signaled when the API config changes, so that the event loop starts over with a new session
+pub type ApiReloadSignal = Signal<CriticalSectionRawMutex, ()>;
+/// When the event loop last connected to an API endpoint
+pub type ApiConnectedAt = Cell<Option<Instant>>;
+
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/// The restart delay doubles with each session failing in a row, up to this
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+const MIN_AUTO_CHUNK_SIZE: u64 = 1024;
+/// Upper bound of the automatic chunk size, so that the chunk buffer plus its msgpack copy stay
+/// well within the free heap
+const MAX_AUTO_CHUNK_SIZE: u64 = 16 * 1024;
+/// How long sending a single chunk should take with the automatic chunk size. Long enough to
+/// amortize the per-frame overhead, short enough to not stall the event loop.
+const TARGET_CHUNK_DURATION: Duration = Duration::from_millis(250);
+
+/// The chunk size to start with before we know the throughput, based on the signal strength
+fn initial_chunk_size() -> u64 {
+    match link_quality().map(|link_quality| link_quality.rssi) {
+        Ok(rssi) if rssi >= -60 => 8 * 1024,
+        Ok(rssi) if rssi >= -70 => 4 * 1024,
+        Ok(_) => 2 * 1024,
+        Err(_) => 4 * 1024,
+    }
+}
+
+/// The chunk size which would take about `TARGET_CHUNK_DURATION` to send at the measured
+/// throughput. It changes by at most a factor of two each time to smooth out the jitters.
+fn next_chunk_size(chunk_size: u64, sent_bytes: usize, elapsed: Duration) -> u64 {
+    let elapsed_micros = elapsed.as_micros().max(1) as u64;
+    let target = sent_bytes as u64 * TARGET_CHUNK_DURATION.as_micros() as u64 / elapsed_micros;
+    target
+        .clamp(chunk_size / 2, chunk_size * 2)
+        .clamp(MIN_AUTO_CHUNK_SIZE, MAX_AUTO_CHUNK_SIZE)
+}
+
+/// Map a path from a request into a path under the root dir, refusing anything escaping it.
+/// Absolute paths, like the ones in the listings, have to be under the root dir, and relative
+/// ones are relative to it.
+pub fn resolve_path(root_dir: &str, path: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    let relative = if path.is_absolute() {
+        path.strip_prefix(root_dir)
+            .map_err(|_| anyhow!("Path {path:?} is outside of {root_dir}"))?
+    } else {
+        path
+    };
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        bail!("Invalid path {path:?}");
+    }
+    Ok(Path::new(root_dir).join(relative))
+}
 
 pub struct Processor {
     pub device_info_producer: DeviceInfoProducer,
     pub root_dir: String,
+    pub active_endpoint: Option<String>,
+    pub wifi_scanner: Option<WifiScanner>,
+    pub config_editor: Option<Arc<ConfigEditor>>,
 }
 
 impl Processor {
     fn get_info(&self) -> anyhow::Result<Response> {
-        let result = (self.device_info_producer)();
+        let result = (self.device_info_producer)().map(|device_info| DeviceInfo {
+            api_endpoint: self.active_endpoint.clone(),
+            ..device_info
+        });
         if let Ok(device_info) = &result {
             log::info!("Get device info {device_info:#?}");
         }
@@ -90,7 +224,7 @@ impl Processor {
     }
 
     fn list_files(&self, path: &str) -> anyhow::Result<Response> {
-        let dir_path = Path::new(&self.root_dir).join(path);
+        let dir_path = resolve_path(&self.root_dir, path)?;
         log::info!(
             "Listing files at {:?}",
             dir_path.to_str().unwrap_or("<Unknown>")
@@ -100,16 +234,9 @@ impl Processor {
         let mut files: Vec<File> = vec![];
         for entry in read_dir(dir_path)? {
             let entry = entry?;
-            let path = entry.path().into_os_string().into_string().map_err(|e| {
-                anyhow!(
-                    "Failed to decode path with error: {}",
-                    e.into_string().unwrap_or(String::from("Unknown"))
-                )
-            });
-            if path.is_err() {
+            let Ok(path) = entry.path().into_os_string().into_string() else {
                 continue;
-            }
-            let path = path.unwrap();
+            };
             let metadata = entry.metadata()?;
             files.push(File {
                 path,
@@ -131,30 +258,50 @@ impl Processor {
         &self,
         req_id: &str,
         path: &str,
-        chunk_size: u64,
+        chunk_size: ChunkSize,
         mut send: F,
     ) -> anyhow::Result<()>
     where
-        F: FnMut(CommandResponse),
+        F: FnMut(CommandResponse) -> anyhow::Result<()>,
     {
-        log::info!("Fetch file at {:?}, chunk_size={}", path, chunk_size);
-        let mut file = std::fs::File::open(path)?;
+        log::info!("Fetch file at {:?}, chunk_size={:?}", path, chunk_size);
+        let (mut chunk_size, is_auto) = match chunk_size {
+            ChunkSize::Fixed(0) => bail!("Chunk size cannot be zero"),
+            ChunkSize::Fixed(chunk_size) => (chunk_size, false),
+            ChunkSize::Mode(ChunkSizeMode::Auto) => (initial_chunk_size(), true),
+        };
+        let mut file = std::fs::File::open(resolve_path(&self.root_dir, path)?)?;
         let file_size = file.metadata()?.len();
-        let mut buf = vec![0; chunk_size as usize];
+        let buf_size = if is_auto {
+            MAX_AUTO_CHUNK_SIZE
+        } else {
+            chunk_size
+        };
+        let mut buf = vec![0; buf_size as usize];
         let mut count: usize = 0;
         let mut total_bytes: usize = 0;
-        for offset in (0..file_size).step_by(chunk_size as usize) {
-            let read_size = file.read(&mut buf)?;
+        let mut offset: u64 = 0;
+        while offset < file_size {
+            let read_size = file.read(&mut buf[..chunk_size as usize])?;
+            if read_size == 0 {
+                break;
+            }
             // TODO: somehow stream_position doesn't work correctly?
             // assert_eq!(file.stream_position().unwrap(), offset);
+            let started_at = Instant::now();
             send(CommandResponse {
                 id: req_id.to_string(),
                 response: FetchFileChunk {
                     offset,
                     data: &buf[..read_size],
-                    is_final: offset + chunk_size >= file_size,
+                    is_final: offset + read_size as u64 >= file_size,
                 },
-            });
+            })
+            .with_context(|| format!("Failed to send chunk at offset {offset}"))?;
+            if is_auto {
+                chunk_size = next_chunk_size(chunk_size, read_size, started_at.elapsed());
+            }
+            offset += read_size as u64;
             count += 1;
             total_bytes += read_size;
         }
@@ -167,14 +314,46 @@ impl Processor {
         Ok(())
     }
 
+    async fn scan_wifi(&self) -> anyhow::Result<Response> {
+        let wifi_scanner = self
+            .wifi_scanner
+            .as_ref()
+            .ok_or_else(|| anyhow!("Wifi is not available"))?;
+        let networks = wifi_scanner().await?;
+        log::info!("Scanned {} Wifi networks", networks.len());
+        Ok(ScanWifi { networks })
+    }
+
+    fn editor(&self) -> anyhow::Result<&ConfigEditor> {
+        self.config_editor
+            .as_deref()
+            .ok_or_else(|| anyhow!("Config is not available"))
+    }
+
+    fn get_config(&self) -> anyhow::Result<Response> {
+        let config = self.editor()?.get()?;
+        Ok(GetConfig { config })
+    }
+
+    fn update_config(&self, patch: &ConfigPatch) -> anyhow::Result<Response> {
+        log::info!("Update config with {patch:?}");
+        let updated = self.editor()?.update(patch)?;
+        Ok(UpdateConfig {
+            config: updated.config,
+            warnings: updated.warnings,
+        })
+    }
+
     fn reboot(&self) -> anyhow::Result<Response> {
         // TODO: reboot
         Ok(Reboot {})
     }
 
-    pub fn process<F>(&self, request: &CommandRequest, mut send: F)
+    /// Process the request and send back the response. An error is only returned when we fail to
+    /// send, in which case the request is aborted as there's no way to tell the other side.
+    pub async fn process<F>(&self, request: &CommandRequest, mut send: F) -> anyhow::Result<()>
     where
-        F: FnMut(CommandResponse),
+        F: FnMut(CommandResponse) -> anyhow::Result<()>,
     {
         let response: anyhow::Result<Response> = match &request.command {
             Command::GetInfo => self.get_info(),
@@ -182,66 +361,173 @@ impl Processor {
             Command::FetchFile { path, chunk_size } => {
                 match self.fetch_file(&*request.id, path, *chunk_size, &mut send) {
                     Ok(_) => {
-                        return;
+                        return Ok(());
                     }
                     Err(error) => Err(error),
                 }
             }
             Command::Reboot => self.reboot(),
+            Command::ScanWifi => self.scan_wifi().await,
+            Command::GetConfig => self.get_config(),
+            Command::UpdateConfig { patch } => self.update_config(patch),
         };
         send(CommandResponse {
             id: request.id.clone(),
             response: response.unwrap_or_else(|error| Error {
                 message: error.to_string(),
             }),
-        });
+        })
+    }
+}
+
+fn flush_outbox(client: &mut dyn Transport, outbox: Option<&RefCell<Outbox>>) {
+    let Some(outbox) = outbox else {
+        return;
+    };
+    if client.get_connection_state() != ConnectionState::Connected {
+        return;
+    }
+    let result = outbox.borrow_mut().flush(|record| {
+        client.send(
+            None,
+            FrameType::Text(false),
+            serde_json::to_string(record)?.as_bytes(),
+        )?;
+        Ok(())
+    });
+    if let Err(error) = result {
+        log::warn!("Failed to flush outbox with error: {error}");
     }
 }
 
 pub async fn process_events(
-    mut client: WebSocketSession<'_>,
+    mut client: Box<dyn Transport + '_>,
     device_info_producer: DeviceInfoProducer,
     root_dir: String,
-) {
-    let mut processor: Option<Box<Processor>> = Some(Box::new(Processor {
+    wifi_scanner: Option<WifiScanner>,
+    config_editor: Option<Arc<ConfigEditor>>,
+    outbox: Option<Rc<RefCell<Outbox>>>,
+    wifi_status: SharedWifiStatus,
+    wifi_state: Rc<WifiStateSignal>,
+    api_reload: Rc<ApiReloadSignal>,
+    api_connected_at: &ApiConnectedAt,
+) -> anyhow::Result<()> {
+    let outbox_notifier = outbox.as_ref().map(|outbox| outbox.borrow().notifier());
+    // The indexes of the endpoints which failed since the last connection. Some transports report
+    // more than one disconnection per endpoint, so they're counted only once.
+    let mut failed_endpoints: HashSet<usize> = HashSet::new();
+    let mut processor = Processor {
         device_info_producer,
         root_dir,
-    }));
-    client.connect();
+        active_endpoint: None,
+        wifi_scanner,
+        config_editor,
+    };
+    // Otherwise wait for the Wi-Fi supervisor to tell us it's connected
+    if wifi_status.read().unwrap().state == WifiState::Connected {
+        client.connect()?;
+    }
 
     loop {
         log::info!("Reading events ...");
-        let event = client.acquire_receiver().unwrap().receive().await;
+        let receiver = client.acquire_receiver();
+        let event = match select(
+            pin!(receiver.unwrap().receive()),
+            select(
+                pin!(async {
+                    match &outbox_notifier {
+                        Some(notifier) => notifier.wait().await,
+                        None => pending().await,
+                    }
+                }),
+                select(pin!(wifi_state.wait()), pin!(api_reload.wait())),
+            ),
+        )
+        .await
+        {
+            Either::Left((event, _)) => event,
+            Either::Right((Either::Left(_), _)) => {
+                flush_outbox(client.as_mut(), outbox.as_deref());
+                continue;
+            }
+            Either::Right((Either::Right((Either::Left((WifiState::Connected, _)), _)), _)) => {
+                log::info!("Wifi is back, reconnect to API endpoint");
+                failed_endpoints.clear();
+                client.disconnect();
+                client.connect()?;
+                continue;
+            }
+            Either::Right((Either::Right((Either::Left((WifiState::Disconnected, _)), _)), _)) => {
+                log::info!("Wifi is gone, disconnect from API endpoint");
+                client.disconnect();
+                continue;
+            }
+            Either::Right((Either::Right((Either::Right(_), _)), _)) => {
+                log::info!("API config changed, disconnect from API endpoint");
+                client.disconnect();
+                return Ok(());
+            }
+        };
         match event {
             SessionEvent::StateChange {
                 new_state: ConnectionState::Connected,
                 ..
             } => {
-                let last_processor = processor.unwrap();
-                processor = Some(Box::new(Processor { ..*last_processor }));
+                failed_endpoints.clear();
+                api_connected_at.set(Some(Instant::now()));
+                processor.active_endpoint = client
+                    .active_endpoint()
+                    .map(|endpoint| endpoint.url.clone());
+                if let Err(error) = client.on_connected() {
+                    log::error!("Failed to set up connection with error: {error:#}");
+                }
+                flush_outbox(client.as_mut(), outbox.as_deref());
+            }
+            SessionEvent::StateChange {
+                new_state: ConnectionState::Disconnected,
+                ..
+            } => {
+                if let DesiredState::Connected = client.get_desired_state() {
+                    failed_endpoints.insert(client.endpoint_index());
+                    // After a full round failing all of them, start over with a new session after
+                    // the backoff, so that the endpoints can be discovered again
+                    if failed_endpoints.len() >= client.endpoint_count() {
+                        bail!(
+                            "Failed to connect to all {} API endpoints",
+                            failed_endpoints.len()
+                        );
+                    }
+                    log::warn!("Connection to API endpoint failed, rotate to the next one");
+                    if let Err(error) = client.rotate_endpoint() {
+                        log::error!("Failed to rotate API endpoint with error: {error:?}");
+                    }
+                }
             }
             SessionEvent::ReceiveText { text } => {
                 let request: serde_json::Result<CommandRequest> = serde_json::from_str(&text);
                 match request {
                     Ok(request) => {
                         log::info!("Processing request {:?}", request);
-                        processor.as_mut().unwrap().process(
-                            &request,
-                            |response: CommandResponse| match response.response {
-                                FetchFileChunk { .. } => client
-                                    .send(
+                        let result = processor
+                            .process(&request, |response: CommandResponse| {
+                                match response.response {
+                                    FetchFileChunk { .. } => client.send(
+                                        Some(&response.id),
                                         FrameType::Binary(false),
-                                        &rmp_serde::to_vec(&response).unwrap(),
-                                    )
-                                    .unwrap(),
-                                _ => client
-                                    .send(
+                                        &rmp_serde::to_vec(&response)?,
+                                    )?,
+                                    _ => client.send(
+                                        Some(&response.id),
                                         FrameType::Text(false),
-                                        serde_json::to_string(&response).unwrap().as_bytes(),
-                                    )
-                                    .unwrap(),
-                            },
-                        )
+                                        serde_json::to_string(&response)?.as_bytes(),
+                                    )?,
+                                }
+                                Ok(())
+                            })
+                            .await;
+                        if let Err(error) = result {
+                            log::error!("Aborted request {} with error: {error:#}", request.id);
+                        }
                     }
                     Err(error) => {
                         log::error!("Failed to parse payload with error: {error}")
@@ -252,3 +538,57 @@ pub async fn process_events(
         }
     }
 }
+
+/// Keep the event loop running. Whenever it fails, a new session is created from the factory and
+/// the loop is started again after a delay, which backs off while the sessions keep failing
+/// without ever connecting. When the API config changes, it's started again right away.
+pub async fn supervise_events(
+    mut session_factory: SessionFactory<'_>,
+    root_dir: String,
+    wifi_scanner: Option<WifiScanner>,
+    config_editor: Option<Arc<ConfigEditor>>,
+    outbox: Option<Rc<RefCell<Outbox>>>,
+    wifi_status: SharedWifiStatus,
+    wifi_state: Rc<WifiStateSignal>,
+    api_reload: Rc<ApiReloadSignal>,
+    api_connected_at: Rc<ApiConnectedAt>,
+) -> anyhow::Result<()> {
+    let timer_service = EspTaskTimerService::new()?;
+    let mut timer = timer_service.timer_async()?;
+    let mut restart_delay = RESTART_DELAY;
+    loop {
+        let started_at = Instant::now();
+        let result = match session_factory().await {
+            Ok((client, device_info_producer)) => {
+                process_events(
+                    client,
+                    device_info_producer,
+                    root_dir.clone(),
+                    wifi_scanner.clone(),
+                    config_editor.clone(),
+                    outbox.clone(),
+                    wifi_status.clone(),
+                    wifi_state.clone(),
+                    api_reload.clone(),
+                    &api_connected_at,
+                )
+                .await
+            }
+            Err(error) => Err(error),
+        };
+        if api_connected_at.get() > Some(started_at) {
+            restart_delay = RESTART_DELAY;
+        }
+        match result {
+            Ok(()) => log::info!("Restart event loop with the new API config"),
+            Err(error) => {
+                log::error!(
+                    "Event loop stopped with error: {error:#}, restart in {} secs",
+                    restart_delay.as_secs()
+                );
+                timer.after(restart_delay).await?;
+                restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::config::Endpoint;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, Receiver};
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::ws::client::WebSocketClosingReason;
+use std::sync::Arc;
+
+pub const STATE_CHANNEL_QUEUE_SIZE: usize = 32;
+
+pub type SessionChannel = Channel<CriticalSectionRawMutex, SessionEvent, STATE_CHANNEL_QUEUE_SIZE>;
+
+#[derive(Debug, Copy, Clone)]
+pub enum DesiredState {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ConnectionState {
+    Connecting,
+    BeforeConnect,
+    Connected,
+    Close {
+        reason: Option<WebSocketClosingReason>,
+    },
+    Closed,
+    Disconnected,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionEvent {
+    StateChange {
+        old_state: ConnectionState,
+        new_state: ConnectionState,
+    },
+    ReceiveText {
+        text: String,
+    },
+}
+
+pub struct ChannelReceiver {
+    channel: Arc<SessionChannel>,
+}
+
+impl ChannelReceiver {
+    pub fn new(channel: Arc<SessionChannel>) -> Self {
+        Self { channel }
+    }
+
+    pub fn unwrap(
+        &self,
+    ) -> Receiver<CriticalSectionRawMutex, SessionEvent, STATE_CHANNEL_QUEUE_SIZE> {
+        self.channel.receiver()
+    }
+}
+
+/// A way of carrying `CommandRequest` from the server and `CommandResponse` back to it.
+pub trait Transport {
+    fn get_desired_state(&self) -> DesiredState;
+
+    fn get_connection_state(&self) -> ConnectionState;
+
+    fn active_endpoint(&self) -> Option<&Endpoint>;
+
+    fn endpoint_count(&self) -> usize;
+
+    /// The index of the active endpoint, which tells apart endpoints sharing the same URL
+    fn endpoint_index(&self) -> usize;
+
+    fn connect(&mut self) -> anyhow::Result<()>;
+
+    /// Called once the connection is established, before any command is processed
+    fn on_connected(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self);
+
+    fn rotate_endpoint(&mut self) -> anyhow::Result<()>;
+
+    fn acquire_receiver(&mut self) -> ChannelReceiver;
+
+    /// Send a frame to the server. The `request_id` is the id of the request it responds to, or
+    /// `None` for events not triggered by any request.
+    fn send(
+        &mut self,
+        request_id: Option<&str>,
+        frame_type: FrameType,
+        frame_data: &[u8],
+    ) -> anyhow::Result<()>;
+}
@@ -1,95 +1,63 @@
+use crate::api::transport::{
+    ChannelReceiver, ConnectionState, DesiredState, SessionChannel, SessionEvent, Transport,
+};
+use crate::config::Endpoint;
 use core::time;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::{Channel, Receiver};
 use embedded_svc::ws::FrameType;
 use esp_idf_svc::hal::task::block_on;
 use esp_idf_svc::io::EspIOError;
 use esp_idf_svc::sys::EspError;
 use esp_idf_svc::ws::client::{
-    EspWebSocketClient, EspWebSocketClientConfig, WebSocketClosingReason, WebSocketEvent,
-    WebSocketEventType,
+    EspWebSocketClient, EspWebSocketClientConfig, WebSocketEvent, WebSocketEventType,
 };
 use std::cmp::PartialEq;
+use std::fmt::{Display, Formatter};
 use std::sync::{Arc, RwLock};
 
-const STATE_CHANNEL_QUEUE_SIZE: usize = 32;
-
 #[derive(Debug, PartialEq)]
 pub enum WebSocketSessionError {
     AlreadyConnected,
     NotConnectedYet,
+    NoEndpoint,
     EspError { error: EspError },
     EspIOError { error: EspIOError },
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum DesiredState {
-    Connected,
-    Disconnected,
+impl Display for WebSocketSessionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketSessionError::AlreadyConnected => write!(f, "Already connected"),
+            WebSocketSessionError::NotConnectedYet => write!(f, "Not connected yet"),
+            WebSocketSessionError::NoEndpoint => write!(f, "No endpoint configured"),
+            WebSocketSessionError::EspError { error } => write!(f, "{error}"),
+            WebSocketSessionError::EspIOError { error } => write!(f, "{error}"),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum ConnectionState {
-    Connecting,
-    BeforeConnect,
-    Connected,
-    Close {
-        reason: Option<WebSocketClosingReason>,
-    },
-    Closed,
-    Disconnected,
-}
-#[derive(Debug, PartialEq)]
-pub enum SessionEvent {
-    StateChange {
-        old_state: ConnectionState,
-        new_state: ConnectionState,
-    },
-    ReceiveText {
-        text: String,
-    },
-}
+impl std::error::Error for WebSocketSessionError {}
 
 struct SessionState {
     desired_state: DesiredState,
     connection_state: ConnectionState,
-    channel: Arc<Channel<CriticalSectionRawMutex, SessionEvent, STATE_CHANNEL_QUEUE_SIZE>>,
-}
-
-pub struct ChannelReceiver {
-    channel: Arc<Channel<CriticalSectionRawMutex, SessionEvent, STATE_CHANNEL_QUEUE_SIZE>>,
-}
-
-impl ChannelReceiver {
-    pub fn unwrap(
-        &self,
-    ) -> Receiver<CriticalSectionRawMutex, SessionEvent, STATE_CHANNEL_QUEUE_SIZE> {
-        self.channel.receiver()
-    }
+    channel: Arc<SessionChannel>,
 }
 
 pub struct WebSocketSession<'a> {
-    endpoint: String,
+    endpoints: Vec<Endpoint>,
+    endpoint_index: usize,
     timeout: time::Duration,
-    config: EspWebSocketClientConfig<'a>,
     ws_client: Option<EspWebSocketClient<'a>>,
     state: Arc<RwLock<SessionState>>,
 }
 
 impl<'a> WebSocketSession<'a> {
-    pub fn new(endpoint: &str, timeout: time::Duration) -> Self {
-        let channel = Arc::new(Channel::<
-            CriticalSectionRawMutex,
-            SessionEvent,
-            STATE_CHANNEL_QUEUE_SIZE,
-        >::new());
+    pub fn new(endpoints: &[Endpoint], timeout: time::Duration) -> Self {
+        let channel = Arc::new(SessionChannel::new());
         Self {
-            endpoint: endpoint.to_string(),
+            endpoints: endpoints.to_vec(),
+            endpoint_index: 0,
             timeout,
-            config: EspWebSocketClientConfig {
-                // server_cert: Some(X509::pem_until_nul(SERVER_ROOT_CERT)),
-                ..Default::default()
-            },
             ws_client: None,
             state: Arc::new(RwLock::new(SessionState {
                 desired_state: DesiredState::Disconnected,
@@ -107,6 +75,10 @@ impl<'a> WebSocketSession<'a> {
         self.state.read().unwrap().connection_state
     }
 
+    pub fn active_endpoint(&self) -> Option<&Endpoint> {
+        self.endpoints.get(self.endpoint_index)
+    }
+
     pub fn connect(&mut self) -> Result<(), WebSocketSessionError> {
         let mut write_lock = self.state.write();
         let state = write_lock.as_mut().unwrap();
@@ -115,10 +87,25 @@ impl<'a> WebSocketSession<'a> {
             log::info!("Already in {conn_state:?} state, do nothing");
             return Err(WebSocketSessionError::AlreadyConnected);
         }
+        let endpoint = self
+            .endpoints
+            .get(self.endpoint_index)
+            .ok_or(WebSocketSessionError::NoEndpoint)?;
         state.desired_state = DesiredState::Connected;
+        let headers = endpoint
+            .token
+            .as_ref()
+            .map(|token| format!("Authorization: Bearer {token}\r\n"));
+        let config = EspWebSocketClientConfig {
+            // server_cert: Some(X509::pem_until_nul(SERVER_ROOT_CERT)),
+            username: endpoint.username.as_deref(),
+            password: endpoint.password.as_deref(),
+            headers: headers.as_deref(),
+            ..Default::default()
+        };
         let weak_state = Arc::downgrade(&self.state);
         self.ws_client = Some(
-            EspWebSocketClient::new(&self.endpoint, &self.config, self.timeout, move |event| {
+            EspWebSocketClient::new(&endpoint.url, &config, self.timeout, move |event| {
                 let state = weak_state.upgrade();
                 if let Some(state) = state {
                     state.write().unwrap().handle_event(event);
@@ -126,22 +113,40 @@ impl<'a> WebSocketSession<'a> {
             })
             .map_err(|error| WebSocketSessionError::EspIOError { error })?,
         );
-        log::info!("Change desired state to Connected");
+        log::info!(
+            "Change desired state to Connected, endpoint={}, index={}",
+            endpoint.url,
+            self.endpoint_index
+        );
         Ok(())
     }
 
     pub fn disconnect(&mut self) {
-        let mut write_lock = self.state.write();
-        let state = write_lock.as_mut().unwrap();
-        self.ws_client = None;
-        state.desired_state = DesiredState::Disconnected;
+        let ws_client = self.ws_client.take();
+        {
+            let mut state = self.state.write().unwrap();
+            state.desired_state = DesiredState::Disconnected;
+            state.connection_state = ConnectionState::Disconnected;
+        }
+        // Dropping the client waits for its task to stop, which may be blocked in the event
+        // callback on the state lock, so it's only dropped once the lock is released
+        drop(ws_client);
         log::info!("Change desired state to Disconnected")
     }
 
-    pub fn acquire_receiver(&mut self) -> ChannelReceiver {
-        ChannelReceiver {
-            channel: self.state.read().unwrap().channel.clone(),
+    /// Drop the current connection and connect to the next endpoint in the list, wrapping back
+    /// to the first (highest priority) one after the last.
+    pub fn rotate_endpoint(&mut self) -> Result<(), WebSocketSessionError> {
+        if self.endpoints.is_empty() {
+            return Err(WebSocketSessionError::NoEndpoint);
         }
+        self.disconnect();
+        self.endpoint_index = (self.endpoint_index + 1) % self.endpoints.len();
+        self.connect()
+    }
+
+    pub fn acquire_receiver(&mut self) -> ChannelReceiver {
+        ChannelReceiver::new(self.state.read().unwrap().channel.clone())
     }
 
     pub fn send(
@@ -161,6 +166,53 @@ impl<'a> WebSocketSession<'a> {
     }
 }
 
+impl Transport for WebSocketSession<'_> {
+    fn get_desired_state(&self) -> DesiredState {
+        WebSocketSession::get_desired_state(self)
+    }
+
+    fn get_connection_state(&self) -> ConnectionState {
+        WebSocketSession::get_connection_state(self)
+    }
+
+    fn active_endpoint(&self) -> Option<&Endpoint> {
+        WebSocketSession::active_endpoint(self)
+    }
+
+    fn endpoint_count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    fn endpoint_index(&self) -> usize {
+        self.endpoint_index
+    }
+
+    fn connect(&mut self) -> anyhow::Result<()> {
+        Ok(WebSocketSession::connect(self)?)
+    }
+
+    fn disconnect(&mut self) {
+        WebSocketSession::disconnect(self)
+    }
+
+    fn rotate_endpoint(&mut self) -> anyhow::Result<()> {
+        Ok(WebSocketSession::rotate_endpoint(self)?)
+    }
+
+    fn acquire_receiver(&mut self) -> ChannelReceiver {
+        WebSocketSession::acquire_receiver(self)
+    }
+
+    fn send(
+        &mut self,
+        _request_id: Option<&str>,
+        frame_type: FrameType,
+        frame_data: &[u8],
+    ) -> anyhow::Result<()> {
+        Ok(WebSocketSession::send(self, frame_type, frame_data)?)
+    }
+}
+
 impl SessionState {
     fn set_state(&mut self, new_state: ConnectionState) {
         let old_state = self.connection_state;
@@ -1,9 +1,17 @@
+pub mod editor;
+pub mod migrate;
+pub mod paths;
+pub mod store;
+pub mod validate;
+pub mod watcher;
+
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
-use std::io::Read;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub enum AuthMethod {
     None,
     WEP,
@@ -16,52 +24,302 @@ pub enum AuthMethod {
     WAPIPersonal,
 }
 
-#[derive(Deserialize)]
-pub struct Wifi {
+/// WPA2-Enterprise credentials. The certificate and key paths are relative to the volume root.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct Eap {
+    pub identity: Option<String>,
+    /// The outer identity sent in the clear, `identity` is used when not set
+    pub anonymous_identity: Option<String>,
+    /// The inner username for PEAP or TTLS, `identity` is used when not set
+    pub username: Option<String>,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub client_key_password: Option<String>,
+}
+
+impl Debug for Eap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Eap")
+            .field("identity", &self.identity)
+            .field("anonymous_identity", &self.anonymous_identity)
+            .field("username", &self.username)
+            .field("ca_cert", &self.ca_cert)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key)
+            .field(
+                "client_key_password",
+                &self.client_key_password.as_ref().map(|_| "****"),
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct WifiNetwork {
     pub ssid: String,
     pub auth_method: Option<AuthMethod>,
-    // TODO: provide a default way of not storing plaintext? while the key is probably going to
-    //       be in the repo or somewhere, but at least they need to know in order to decode?
     pub password: Option<String>,
+    /// Networks with higher priority are preferred when more than one of them are in range
+    #[serde(default)]
+    pub priority: i32,
+    /// Hidden networks don't show up in scans, so they're tried even when not seen
+    #[serde(default)]
+    pub hidden: bool,
+    pub eap: Option<Eap>,
 }
 
-impl Debug for Wifi {
+impl Debug for WifiNetwork {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Wifi")
+        f.debug_struct("WifiNetwork")
             .field("ssid", &self.ssid)
             .field("auth_method", &self.auth_method)
             .field("password", &"****")
+            .field("priority", &self.priority)
+            .field("hidden", &self.hidden)
+            .field("eap", &self.eap)
             .finish()
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// IP settings of the station interface. DHCP is used unless `address` is set.
+#[derive(Debug, Default, Deserialize, Clone, PartialEq)]
+pub struct WifiIp {
+    pub address: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub netmask: Option<Ipv4Addr>,
+    /// Up to two DNS servers, overriding the ones from DHCP
+    #[serde(default)]
+    pub dns: Vec<Ipv4Addr>,
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Wifi {
+    #[serde(default)]
+    pub networks: Vec<WifiNetwork>,
+    pub ip: Option<WifiIp>,
+}
+
+impl Wifi {
+    /// All the configured networks, ordered by priority from high to low. Networks with the same
+    /// priority keep their order in the file.
+    pub fn networks(&self) -> Vec<WifiNetwork> {
+        let mut networks = self.networks.clone();
+        networks.sort_by(|a, b| b.priority.cmp(&a.priority));
+        networks
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct Endpoint {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Debug for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "****"))
+            .field("token", &self.token.as_ref().map(|_| "****"))
+            .finish()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiTransport {
+    #[default]
+    WebSocket,
+    Http,
+    Mqtt,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Api {
-    pub endpoint: String,
+    /// Tried in order
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    pub transport: ApiTransport,
+    /// Look up `_securedash._tcp` services with mDNS and try them before the configured endpoints
+    #[serde(default)]
+    pub discover: bool,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_http_port() -> u16 {
+    80
+}
+
+fn default_http_username() -> String {
+    "securedash".to_string()
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct Http {
+    #[serde(default = "default_http_port")]
+    pub port: u16,
+    #[serde(default = "default_http_username")]
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for Http {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Http")
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &"****")
+            .finish()
+    }
+}
+
+/// USB mass storage settings, applied at boot
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct Usb {
+    /// USB 2.0 high speed, which the USB controller of ESP32-S3 doesn't support. Older configs
+    /// have it set to `true`, so it's accepted with a warning and ignored.
     pub high_speed: bool,
+    /// Report the drive as write-protected to the USB host
+    pub read_only: bool,
 }
 
-impl Default for Usb {
+/// SD card host settings, applied at boot. When the card doesn't work with them, slower
+/// frequencies and then the 1-bit bus are tried.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Sd {
+    /// 1 or 4 data lines
+    pub bus_width: u8,
+    pub frequency_khz: u32,
+    /// The ESP32-S3-USB-OTG board has external pullups, others may need the internal ones
+    pub internal_pullups: bool,
+}
+
+impl Default for Sd {
     fn default() -> Self {
-        Self { high_speed: true }
+        Self {
+            bus_width: 4,
+            frequency_khz: 52_000,
+            internal_pullups: false,
+        }
     }
 }
 
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ScheduleWindow {
+    /// In `HH:MM` format
+    pub start: String,
+    /// In `HH:MM` format, windows ending before they start span across midnight
+    pub end: String,
+}
+
+/// What to do with Wi-Fi outside the scheduled windows. There's no `min_modem`, as that's the
+/// power save mode while active anyway.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleMode {
+    #[default]
+    WifiOff,
+    MaxModem,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Schedule {
+    pub windows: Vec<ScheduleWindow>,
+    /// The offset of the local time the windows are in, like `-08:00`. Defaults to UTC.
+    pub utc_offset: Option<String>,
+    #[serde(default)]
+    pub idle: IdleMode,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Log {
+    /// The level of everything, defaults to the one the firmware was built with
+    pub level: Option<LogLevel>,
+    /// Levels of specific targets, which are the full Rust module paths like
+    /// `securedash::api::processor`
+    #[serde(default)]
+    pub targets: BTreeMap<String, LogLevel>,
+}
+
+/// Where the config in use was loaded from
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigSource {
+    /// The `securedash.toml` file on the drive
+    File,
+    /// The last known-good config stored in NVS
+    Nvs,
+}
+
+/// The source of the config in use, updated whenever another config is applied
+pub type SharedConfigSource = Arc<RwLock<ConfigSource>>;
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub wifi: Wifi,
     pub api: Api,
+    pub http: Option<Http>,
     pub usb: Option<Usb>,
+    pub sd: Option<Sd>,
+    pub schedule: Option<Schedule>,
+    pub log: Option<Log>,
+}
+
+/// Replace the `{ encrypted = "..." }` tables anywhere in the value with their plaintext
+fn decrypt_values(
+    value: &mut toml::Value,
+    decrypt: &dyn Fn(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    match value {
+        toml::Value::Table(table) => {
+            if let (1, Some(toml::Value::String(encrypted))) = (table.len(), table.get("encrypted"))
+            {
+                *value = toml::Value::String(decrypt(encrypted)?);
+                return Ok(());
+            }
+            for item in table.values_mut() {
+                decrypt_values(item, decrypt)?;
+            }
+        }
+        toml::Value::Array(items) => {
+            for item in items {
+                decrypt_values(item, decrypt)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
 }
 
 impl Config {
-    pub fn read(file_path: &str) -> anyhow::Result<Self> {
-        let mut config_str = String::new();
-        File::open(file_path)?.read_to_string(&mut config_str)?;
-        Ok(toml::from_str(&*config_str)?)
+    /// Parse the config file content, migrated to the current schema version, with any
+    /// `{ encrypted = "..." }` value replaced by the plaintext from the given decrypt function
+    pub fn parse(
+        content: &str,
+        decrypt: &dyn Fn(&str) -> anyhow::Result<String>,
+    ) -> anyhow::Result<Self> {
+        let mut table: toml::Table = content.parse()?;
+        migrate::migrate(&mut table)?;
+        let mut value = toml::Value::Table(table);
+        decrypt_values(&mut value, decrypt)?;
+        Ok(value.try_into()?)
     }
 }
@@ -0,0 +1,262 @@
+use crate::config::migrate::migrate;
+use crate::config::validate::{validate, Severity};
+use anyhow::{anyhow, bail};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use toml::{Table, Value};
+
+/// Shown in place of the secrets. Sending it back in a patch keeps the current value.
+pub const REDACTED: &str = "****";
+const SECRET_KEYS: &[&str] = &["password", "token", "client_key_password"];
+/// The keys identifying the items of the lists holding secrets, `wifi.networks` and
+/// `api.endpoints`
+const ITEM_ID_KEYS: &[&str] = &["ssid", "url"];
+
+/// A JSON merge patch for the config. It may carry secrets, so only the keys it touches at the
+/// top level are shown in the logs.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(transparent)]
+pub struct ConfigPatch(pub serde_json::Value);
+
+impl Debug for ConfigPatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            serde_json::Value::Object(object) => f.debug_set().entries(object.keys()).finish(),
+            _ => f.write_str("****"),
+        }
+    }
+}
+
+pub type DecryptFn = Box<dyn Fn(&str) -> anyhow::Result<String> + Send + Sync>;
+
+/// The config after an update, with the secrets redacted
+pub struct UpdatedConfig {
+    pub config: serde_json::Value,
+    pub warnings: Vec<String>,
+}
+
+/// Reads and updates the config in use for the `GetConfig` and `UpdateConfig` API commands.
+/// Updates are written to the config file, and the main loop is signaled to apply them along
+/// with the content to roll back to.
+pub struct ConfigEditor {
+    config_file: PathBuf,
+    decrypt: DecryptFn,
+    /// The content of the config in use
+    content: Mutex<String>,
+    updated: Signal<CriticalSectionRawMutex, String>,
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *item = Value::String(REDACTED.to_string());
+                } else {
+                    redact(item);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Find the current item of a list with the same `ssid` or `url` as the item from the patch.
+/// Matching by index would keep the secret of another item once the list is reordered.
+fn current_item<'a>(item: &serde_json::Value, current: Option<&'a Value>) -> Option<&'a Value> {
+    let current = current?.as_array()?;
+    ITEM_ID_KEYS.iter().find_map(|key| {
+        let id = item.get(key)?.as_str()?;
+        current
+            .iter()
+            .find(|current| current.get(key).and_then(Value::as_str) == Some(id))
+    })
+}
+
+/// Convert a value from the patch to TOML. Redacted secrets are replaced with the current value
+/// at the same place, so the patch is rejected if there's none, like for a new item of a list.
+fn to_toml(
+    path: &str,
+    value: &serde_json::Value,
+    current: Option<&Value>,
+) -> anyhow::Result<Value> {
+    Ok(match value {
+        serde_json::Value::Null => bail!("{path} can't be null"),
+        serde_json::Value::Bool(value) => Value::Boolean(*value),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(number) => Value::Integer(number),
+            None => Value::Float(
+                number
+                    .as_f64()
+                    .ok_or_else(|| anyhow!("{path} is out of range"))?,
+            ),
+        },
+        serde_json::Value::String(value) if value == REDACTED => current
+            .cloned()
+            .ok_or_else(|| anyhow!("{path} is redacted, but there's no current value to keep"))?,
+        serde_json::Value::String(value) => Value::String(value.clone()),
+        serde_json::Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    let current = current_item(item, current);
+                    to_toml(&format!("{path}[{index}]"), item, current)
+                })
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        serde_json::Value::Object(object) => {
+            let mut table = Table::new();
+            for (key, item) in object.iter().filter(|(_, item)| !item.is_null()) {
+                let current = current.and_then(|current| current.get(key));
+                table.insert(
+                    key.clone(),
+                    to_toml(&format!("{path}.{key}"), item, current)?,
+                );
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+/// Apply a JSON merge patch (RFC 7396): objects are merged into tables, `null` removes the key,
+/// and anything else, lists included, replaces the current value
+fn merge_patch(
+    path: &str,
+    table: &mut Table,
+    patch: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<()> {
+    for (key, value) in patch {
+        let key_path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        if value.is_null() {
+            table.remove(key);
+            continue;
+        }
+        if let (serde_json::Value::Object(patch), Some(Value::Table(current))) =
+            (value, table.get_mut(key))
+        {
+            merge_patch(&key_path, current, patch)?;
+            continue;
+        }
+        let value = to_toml(&key_path, value, table.get(key))?;
+        table.insert(key.clone(), value);
+    }
+    Ok(())
+}
+
+/// Write the file without ever leaving it half-written. The content goes to a temporary file
+/// first, which then takes the place of the file. FAT can't rename over an existing file, so the
+/// old one is moved aside and only removed once the new one is in place.
+fn write_atomically(path: &Path, content: &str) -> anyhow::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid config path {path:?}"))?
+        .to_string_lossy();
+    let temp_path = path.with_file_name(format!("{file_name}.tmp"));
+    let old_path = path.with_file_name(format!("{file_name}.old"));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    let _ = std::fs::remove_file(&old_path);
+    if path.exists() {
+        std::fs::rename(path, &old_path)?;
+    }
+    std::fs::rename(&temp_path, path)?;
+    let _ = std::fs::remove_file(&old_path);
+    Ok(())
+}
+
+impl ConfigEditor {
+    pub fn new(config_file: &Path, content: String, decrypt: DecryptFn) -> Self {
+        Self {
+            config_file: config_file.to_path_buf(),
+            decrypt,
+            content: Mutex::new(content),
+            updated: Signal::new(),
+        }
+    }
+
+    /// The config in use migrated to the current schema, as a table
+    fn current_table(content: &str) -> anyhow::Result<Table> {
+        let mut table: Table = content.parse()?;
+        migrate(&mut table)?;
+        Ok(table)
+    }
+
+    fn to_redacted_json(table: Table) -> anyhow::Result<serde_json::Value> {
+        let mut value = Value::Table(table);
+        redact(&mut value);
+        Ok(serde_json::to_value(value)?)
+    }
+
+    /// The config in use, with the secrets redacted
+    pub fn get(&self) -> anyhow::Result<serde_json::Value> {
+        let content = self.content.lock().unwrap();
+        Self::to_redacted_json(Self::current_table(&content)?)
+    }
+
+    /// Apply the patch to the config in use, and write it to the config file if it's valid
+    pub fn update(&self, patch: &ConfigPatch) -> anyhow::Result<UpdatedConfig> {
+        let serde_json::Value::Object(patch) = &patch.0 else {
+            bail!("The patch should be an object");
+        };
+        let mut content = self.content.lock().unwrap();
+        let mut table = Self::current_table(&content)?;
+        merge_patch("", &mut table, patch)?;
+        let new_content = toml::to_string(&table)?;
+        let problems = validate(&new_content, &*self.decrypt);
+        // The line numbers are of the generated content, so they're left out
+        let errors: Vec<String> = problems
+            .iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .map(|problem| problem.message.clone())
+            .collect();
+        if !errors.is_empty() {
+            bail!("The patched config is invalid: {}", errors.join("; "));
+        }
+        write_atomically(&self.config_file, &new_content)?;
+        log::info!("Wrote updated config to {:?}", self.config_file);
+        let previous_content = std::mem::replace(&mut *content, new_content);
+        // Updates not applied yet are rolled back together to the content before the first one
+        let previous_content = self.updated.try_take().unwrap_or(previous_content);
+        self.updated.signal(previous_content);
+        Ok(UpdatedConfig {
+            config: Self::to_redacted_json(table)?,
+            warnings: problems
+                .into_iter()
+                .filter(|problem| problem.severity == Severity::Warning)
+                .map(|problem| problem.message)
+                .collect(),
+        })
+    }
+
+    /// Wait for the next update, and return the content before it
+    pub async fn wait_updated(&self) -> String {
+        self.updated.wait().await
+    }
+
+    /// Write the content back to the config file after a failed update
+    pub fn restore(&self, content: String) -> anyhow::Result<()> {
+        let mut current = self.content.lock().unwrap();
+        write_atomically(&self.config_file, &content)?;
+        *current = content;
+        Ok(())
+    }
+
+    /// Keep up with changes to the config file made by the USB host
+    pub fn set_content(&self, content: String) {
+        *self.content.lock().unwrap() = content;
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, bail};
+use toml::{Table, Value};
+
+/// The version of the config layout this firmware reads. Files without `schema_version` are
+/// version 1.
+pub const CURRENT_SCHEMA_VERSION: i64 = 2;
+const LEGACY_SCHEMA_VERSION: i64 = 1;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// The schema version declared by the config
+pub fn schema_version(table: &Table) -> anyhow::Result<i64> {
+    let Some(value) = table.get(SCHEMA_VERSION_KEY) else {
+        return Ok(LEGACY_SCHEMA_VERSION);
+    };
+    let version = value
+        .as_integer()
+        .ok_or_else(|| anyhow!("schema_version should be a number"))?;
+    if !(LEGACY_SCHEMA_VERSION..=CURRENT_SCHEMA_VERSION).contains(&version) {
+        bail!(
+            "schema_version {version} is not supported, this firmware supports up to \
+            {CURRENT_SCHEMA_VERSION}"
+        );
+    }
+    Ok(version)
+}
+
+/// Get the sub-table, `None` if it's missing or not a table
+fn table_mut<'a>(table: &'a mut Table, key: &str) -> Option<&'a mut Table> {
+    table.get_mut(key).and_then(|value| value.as_table_mut())
+}
+
+/// Put the item in front of the array at the key, creating the array if needed
+fn prepend(table: &mut Table, key: &str, item: Table) -> anyhow::Result<()> {
+    let items = table
+        .entry(key)
+        .or_insert_with(|| Value::Array(vec![]))
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("{key} should be a list"))?;
+    items.insert(0, Value::Table(item));
+    Ok(())
+}
+
+/// Version 2 only has lists of networks and endpoints. The single network in `[wifi]` and the
+/// single `endpoint` in `[api]` become the first ones in the lists, which is where they were
+/// sorted to before.
+fn migrate_v1_to_v2(table: &mut Table) -> anyhow::Result<()> {
+    if let Some(wifi) = table_mut(table, "wifi") {
+        if let Some(ssid) = wifi.remove("ssid") {
+            let mut network = Table::new();
+            network.insert("ssid".to_string(), ssid);
+            for key in ["auth_method", "password", "eap"] {
+                if let Some(value) = wifi.remove(key) {
+                    network.insert(key.to_string(), value);
+                }
+            }
+            prepend(wifi, "networks", network)?;
+        }
+    }
+    if let Some(api) = table_mut(table, "api") {
+        if let Some(url) = api.remove("endpoint") {
+            let mut endpoint = Table::new();
+            endpoint.insert("url".to_string(), url);
+            prepend(api, "endpoints", endpoint)?;
+        }
+    }
+    Ok(())
+}
+
+/// Upgrade the config to the current schema version in place, and return the version it was in
+pub fn migrate(table: &mut Table) -> anyhow::Result<i64> {
+    let original_version = schema_version(table)?;
+    let mut version = original_version;
+    while version < CURRENT_SCHEMA_VERSION {
+        match version {
+            1 => migrate_v1_to_v2(table)?,
+            _ => unreachable!("No migration from schema version {version}"),
+        }
+        version += 1;
+    }
+    table.insert(
+        SCHEMA_VERSION_KEY.to_string(),
+        Value::Integer(CURRENT_SCHEMA_VERSION),
+    );
+    Ok(original_version)
+}
@@ -0,0 +1,98 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+const NVS_NAMESPACE: &str = "securedash";
+const PARTITION_LABEL_KEY: &str = "partition_label";
+const MOUNT_PATH_KEY: &str = "mount_path";
+const CONFIG_PATH_KEY: &str = "config_path";
+/// Partition labels are limited to 16 characters by ESP-IDF
+const MAX_PARTITION_LABEL_LEN: usize = 16;
+/// `ESP_VFS_PATH_MAX`, the longest path prefix a file system can be registered at
+const MAX_MOUNT_PATH_LEN: usize = 15;
+const MAX_CONFIG_PATH_LEN: usize = 255;
+
+/// Where the storage and the config file are. The values built into the firmware are the
+/// defaults, and each of them can be overridden per device with a string in the `securedash`
+/// NVS namespace, so that one build works for different layouts.
+#[derive(Debug, Clone)]
+pub struct Paths {
+    pub partition_label: String,
+    pub mount_path: String,
+    /// Relative to the mount path
+    pub config_path: String,
+}
+
+fn is_valid_partition_label(value: &str) -> bool {
+    !value.is_empty() && value.len() <= MAX_PARTITION_LABEL_LEN
+}
+
+fn is_valid_mount_path(value: &str) -> bool {
+    value.len() >= 2
+        && value.len() <= MAX_MOUNT_PATH_LEN
+        && value.starts_with('/')
+        && !value.ends_with('/')
+}
+
+fn is_valid_config_path(value: &str) -> bool {
+    !value.is_empty() && !value.starts_with('/') && !value.split('/').any(|part| part == "..")
+}
+
+/// The value of the key if it's set and valid, the default otherwise
+fn read_override(
+    nvs: &EspNvs<NvsDefault>,
+    key: &str,
+    default: String,
+    is_valid: fn(&str) -> bool,
+) -> String {
+    let mut buf = [0u8; MAX_CONFIG_PATH_LEN + 1];
+    match nvs.get_str(key, &mut buf) {
+        Ok(Some(value)) if is_valid(value) => {
+            log::info!("Override {key} with {value:?} from NVS");
+            value.to_string()
+        }
+        Ok(Some(value)) => {
+            log::warn!("Ignored invalid {key} {value:?} in NVS, using {default:?}");
+            default
+        }
+        Ok(None) => default,
+        Err(error) => {
+            log::warn!("Failed to read {key} from NVS with error: {error:#}, using {default:?}");
+            default
+        }
+    }
+}
+
+impl Paths {
+    /// Apply the overrides in NVS to the defaults
+    pub fn load(defaults: Paths, partition: Option<EspDefaultNvsPartition>) -> Self {
+        let Some(partition) = partition else {
+            return defaults;
+        };
+        let nvs = match EspNvs::new(partition, NVS_NAMESPACE, true) {
+            Ok(nvs) => nvs,
+            Err(error) => {
+                log::warn!("Failed to open NVS for path overrides with error: {error:#}");
+                return defaults;
+            }
+        };
+        Self {
+            partition_label: read_override(
+                &nvs,
+                PARTITION_LABEL_KEY,
+                defaults.partition_label,
+                is_valid_partition_label,
+            ),
+            mount_path: read_override(
+                &nvs,
+                MOUNT_PATH_KEY,
+                defaults.mount_path,
+                is_valid_mount_path,
+            ),
+            config_path: read_override(
+                &nvs,
+                CONFIG_PATH_KEY,
+                defaults.config_path,
+                is_valid_config_path,
+            ),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::bail;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+const NVS_NAMESPACE: &str = "securedash";
+const NVS_KEY: &str = "config";
+/// The NVS partition is small, refuse anything which would take most of it
+const MAX_CONFIG_SIZE: usize = 8 * 1024;
+
+/// Keeps the last known-good config file content in NVS, so that the device can still connect
+/// after the drive is reformatted or swapped. The content is stored as-is, so encrypted values
+/// stay encrypted.
+pub struct ConfigStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl ConfigStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NVS_NAMESPACE, true)?,
+        })
+    }
+
+    pub fn load(&self) -> anyhow::Result<Option<String>> {
+        let Some(len) = self.nvs.blob_len(NVS_KEY)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; len];
+        let content = self.nvs.get_blob(NVS_KEY, &mut buf)?.unwrap_or_default();
+        Ok(Some(String::from_utf8(content.to_vec())?))
+    }
+
+    /// Store the content unless it's the same as the stored one, to spare the flash. Returns
+    /// whether it was written.
+    pub fn save(&mut self, content: &str) -> anyhow::Result<bool> {
+        if content.len() > MAX_CONFIG_SIZE {
+            bail!("Config is too large to store, size={}", content.len());
+        }
+        if self.load()?.as_deref() == Some(content) {
+            return Ok(false);
+        }
+        self.nvs.set_blob(NVS_KEY, content.as_bytes())?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,627 @@
+use crate::config::migrate::{schema_version, CURRENT_SCHEMA_VERSION};
+use crate::config::{ApiTransport, AuthMethod, Config, IdleMode, LogLevel, WifiNetwork};
+use crate::schedule::Schedule;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt;
+
+const MAX_SSID_LEN: usize = 32;
+const MIN_PASSPHRASE_LEN: usize = 8;
+const MAX_PASSPHRASE_LEN: usize = 63;
+/// A raw PSK in hex instead of a passphrase. It's also the most the driver takes for any password.
+const PSK_HEX_LEN: usize = 64;
+/// The SD card frequency range in kHz, from the probing frequency up to the SD high speed mode
+const MIN_SD_FREQUENCY_KHZ: u32 = 400;
+const MAX_SD_FREQUENCY_KHZ: u32 = 52_000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    /// The config is rejected
+    Error,
+    /// The config is still accepted, but it's probably not what the user meant
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        match self.line {
+            Some(line) => write!(f, "{severity} at line {line}: {}", self.message),
+            None => write!(f, "{severity}: {}", self.message),
+        }
+    }
+}
+
+/// The keys allowed in each table of the config
+enum Field {
+    Value,
+    Table(&'static [(&'static str, Field)]),
+    Tables(&'static [(&'static str, Field)]),
+    /// Only allowed before the current schema version, along with what replaced it
+    Legacy(&'static Field, &'static str),
+}
+
+const EAP_FIELDS: &[(&str, Field)] = &[
+    ("identity", Field::Value),
+    ("anonymous_identity", Field::Value),
+    ("username", Field::Value),
+    ("ca_cert", Field::Value),
+    ("client_cert", Field::Value),
+    ("client_key", Field::Value),
+    ("client_key_password", Field::Value),
+];
+
+const WIFI_NETWORK_FIELDS: &[(&str, Field)] = &[
+    ("ssid", Field::Value),
+    ("auth_method", Field::Value),
+    ("password", Field::Value),
+    ("priority", Field::Value),
+    ("hidden", Field::Value),
+    ("eap", Field::Table(EAP_FIELDS)),
+];
+
+const WIFI_IP_FIELDS: &[(&str, Field)] = &[
+    ("address", Field::Value),
+    ("gateway", Field::Value),
+    ("netmask", Field::Value),
+    ("dns", Field::Value),
+    ("hostname", Field::Value),
+];
+
+/// Where the legacy single network keys moved to
+const WIFI_NETWORKS: &str = "[[wifi.networks]]";
+
+const WIFI_FIELDS: &[(&str, Field)] = &[
+    ("ssid", Field::Legacy(&Field::Value, WIFI_NETWORKS)),
+    ("auth_method", Field::Legacy(&Field::Value, WIFI_NETWORKS)),
+    ("password", Field::Legacy(&Field::Value, WIFI_NETWORKS)),
+    (
+        "eap",
+        Field::Legacy(&Field::Table(EAP_FIELDS), WIFI_NETWORKS),
+    ),
+    ("networks", Field::Tables(WIFI_NETWORK_FIELDS)),
+    ("ip", Field::Table(WIFI_IP_FIELDS)),
+];
+
+const ENDPOINT_FIELDS: &[(&str, Field)] = &[
+    ("url", Field::Value),
+    ("username", Field::Value),
+    ("password", Field::Value),
+    ("token", Field::Value),
+];
+
+const API_FIELDS: &[(&str, Field)] = &[
+    (
+        "endpoint",
+        Field::Legacy(&Field::Value, "[[api.endpoints]]"),
+    ),
+    ("endpoints", Field::Tables(ENDPOINT_FIELDS)),
+    ("transport", Field::Value),
+    ("discover", Field::Value),
+];
+
+const HTTP_FIELDS: &[(&str, Field)] = &[
+    ("port", Field::Value),
+    ("username", Field::Value),
+    ("password", Field::Value),
+];
+
+const USB_FIELDS: &[(&str, Field)] = &[("high_speed", Field::Value), ("read_only", Field::Value)];
+
+const SD_FIELDS: &[(&str, Field)] = &[
+    ("bus_width", Field::Value),
+    ("frequency_khz", Field::Value),
+    ("internal_pullups", Field::Value),
+];
+
+const SCHEDULE_WINDOW_FIELDS: &[(&str, Field)] = &[("start", Field::Value), ("end", Field::Value)];
+
+const SCHEDULE_FIELDS: &[(&str, Field)] = &[
+    ("windows", Field::Tables(SCHEDULE_WINDOW_FIELDS)),
+    ("utc_offset", Field::Value),
+    ("idle", Field::Value),
+];
+
+/// The targets are module paths picked by the user, so only their levels are checked
+const LOG_FIELDS: &[(&str, Field)] = &[("level", Field::Value), ("targets", Field::Value)];
+
+const CONFIG_FIELDS: &[(&str, Field)] = &[
+    ("schema_version", Field::Value),
+    ("write_migrated", Field::Value),
+    ("wifi", Field::Table(WIFI_FIELDS)),
+    ("api", Field::Table(API_FIELDS)),
+    ("http", Field::Table(HTTP_FIELDS)),
+    ("usb", Field::Table(USB_FIELDS)),
+    ("sd", Field::Table(SD_FIELDS)),
+    ("schedule", Field::Table(SCHEDULE_FIELDS)),
+    ("log", Field::Table(LOG_FIELDS)),
+];
+
+/// Maps key paths like `wifi.networks[1].ssid` to the line they are defined at. The `toml`
+/// crate doesn't keep the spans of values, so we scan the lines for table headers and keys
+/// instead. Keys in inline tables or dotted keys are not found, and the line of the enclosing
+/// table is used for them instead.
+struct LineIndex {
+    lines: HashMap<String, usize>,
+}
+
+impl LineIndex {
+    fn new(content: &str) -> Self {
+        let mut lines = HashMap::new();
+        let mut array_counts: HashMap<String, usize> = HashMap::new();
+        let mut table_path = String::new();
+        // Turn `wifi.networks.eap` into `wifi.networks[2].eap` if `wifi.networks` is an array
+        let resolve = |name: &str, array_counts: &HashMap<String, usize>| {
+            let mut resolved = String::new();
+            for part in name.split('.') {
+                if !resolved.is_empty() {
+                    resolved.push('.');
+                }
+                resolved.push_str(part.trim().trim_matches('"'));
+                if let Some(count) = array_counts.get(&resolved) {
+                    resolved = format!("{resolved}[{}]", count - 1);
+                }
+            }
+            resolved
+        };
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            let line_number = index + 1;
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line
+                .strip_prefix("[[")
+                .and_then(|rest| rest.split_once("]]"))
+                .map(|(name, _)| name.trim())
+            {
+                let array_path = {
+                    // The array itself may be nested in another array
+                    let (parent, last) = name.rsplit_once('.').unwrap_or(("", name));
+                    let parent = resolve(parent, &array_counts);
+                    if parent.is_empty() {
+                        last.trim().to_string()
+                    } else {
+                        format!("{parent}.{}", last.trim())
+                    }
+                };
+                let count = array_counts.entry(array_path.clone()).or_insert(0);
+                table_path = format!("{array_path}[{count}]");
+                *count += 1;
+                lines.insert(table_path.clone(), line_number);
+            } else if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|rest| rest.split_once(']'))
+                .map(|(name, _)| name.trim())
+            {
+                table_path = resolve(name, &array_counts);
+                lines.insert(table_path.clone(), line_number);
+            } else if let Some((key, _)) = line.split_once('=') {
+                let key = key.trim().trim_matches('"');
+                let path = if table_path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{table_path}.{key}")
+                };
+                lines.entry(path).or_insert(line_number);
+            }
+        }
+        Self { lines }
+    }
+
+    /// The line of the key path, or of the closest parent found
+    fn find(&self, path: &str) -> Option<usize> {
+        let mut path = path;
+        loop {
+            if let Some(line) = self.lines.get(path) {
+                return Some(*line);
+            }
+            let cut = path.rfind(['.', '['])?;
+            path = &path[..cut];
+        }
+    }
+}
+
+struct Validator {
+    line_index: LineIndex,
+    schema_version: i64,
+    problems: Vec<Problem>,
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+fn is_encrypted(value: &toml::Value) -> bool {
+    matches!(value, toml::Value::Table(table) if table.len() == 1 && table.contains_key("encrypted"))
+}
+
+/// A plaintext string value, `None` for missing or encrypted ones
+fn get_str<'a>(table: &'a toml::Table, key: &str) -> Option<&'a str> {
+    table.get(key).and_then(|value| value.as_str())
+}
+
+fn check_url(url: &str, transport: ApiTransport) -> Result<(), String> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("{url:?} is not a valid URL, it should look like ws://host/path"))?;
+    let expected: &[&str] = match transport {
+        ApiTransport::WebSocket => &["ws", "wss"],
+        ApiTransport::Http => &["http", "https"],
+        ApiTransport::Mqtt => &["mqtt", "mqtts", "ws", "wss"],
+    };
+    if !expected.contains(&scheme) {
+        return Err(format!(
+            "URL {url:?} should start with {} for the {transport:?} transport",
+            expected
+                .iter()
+                .map(|scheme| format!("{scheme}://"))
+                .collect::<Vec<_>>()
+                .join(" or ")
+        ));
+    }
+    let authority = rest.split(['/', '?']).next().unwrap_or("");
+    let host_port = authority
+        .rsplit_once('@')
+        .map(|(_, host)| host)
+        .unwrap_or(authority);
+    // IPv6 hosts are in brackets, like `[::1]:8080`
+    let (host, port) = match host_port.rsplit_once(':') {
+        Some((host, port)) if !host_port.ends_with(']') => (host, Some(port)),
+        _ => (host_port, None),
+    };
+    if host.is_empty() {
+        return Err(format!("URL {url:?} is missing the host"));
+    }
+    if let Some(port) = port {
+        if port.parse::<u16>().is_err() {
+            return Err(format!("URL {url:?} has an invalid port {port:?}"));
+        }
+    }
+    Ok(())
+}
+
+impl Validator {
+    /// Whether the config is in an older schema version, to be migrated when it's loaded
+    fn is_legacy(&self) -> bool {
+        self.schema_version < CURRENT_SCHEMA_VERSION
+    }
+
+    fn report(&mut self, severity: Severity, path: &str, message: String) {
+        self.problems.push(Problem {
+            severity,
+            line: self.line_index.find(path),
+            message,
+        });
+    }
+
+    fn check_keys(&mut self, table: &toml::Table, fields: &[(&str, Field)], path: &str) {
+        for (key, value) in table {
+            let key_path = join_path(path, key);
+            let section = if path.is_empty() {
+                "the top level".to_string()
+            } else {
+                format!("[{path}]")
+            };
+            let Some((_, field)) = fields.iter().find(|(name, _)| name == key) else {
+                self.report(
+                    Severity::Warning,
+                    &key_path,
+                    format!("Unknown key {key:?} in {section}, it's ignored"),
+                );
+                continue;
+            };
+            let mut field = field;
+            if let Field::Legacy(legacy_field, replacement) = field {
+                if !self.is_legacy() {
+                    self.report(
+                        Severity::Warning,
+                        &key_path,
+                        format!(
+                            "Key {key:?} in {section} is replaced by {replacement} since \
+                            schema_version {CURRENT_SCHEMA_VERSION}, it's ignored"
+                        ),
+                    );
+                    continue;
+                }
+                field = *legacy_field;
+            }
+            if is_encrypted(value) {
+                continue;
+            }
+            match (field, value) {
+                (Field::Table(fields), toml::Value::Table(table)) => {
+                    self.check_keys(table, fields, &key_path)
+                }
+                (Field::Tables(fields), toml::Value::Array(items)) => {
+                    for (index, item) in items.iter().enumerate() {
+                        if let toml::Value::Table(table) = item {
+                            self.check_keys(table, fields, &format!("{key_path}[{index}]"));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_enum<T: DeserializeOwned>(&mut self, table: &toml::Table, key: &str, path: &str) {
+        let Some(value) = table.get(key) else {
+            return;
+        };
+        if let Err(error) = value.clone().try_into::<T>() {
+            self.report(
+                Severity::Error,
+                &join_path(path, key),
+                format!("Invalid {key}: {}", error.message()),
+            );
+        }
+    }
+
+    fn check_network(&mut self, table: &toml::Table, path: &str) {
+        self.check_enum::<AuthMethod>(table, "auth_method", path);
+    }
+
+    /// The lengths are checked after decryption, so that encrypted SSIDs and passwords can't
+    /// slip through
+    fn check_network_config(&mut self, network: &WifiNetwork, path: &str) {
+        let ssid = &network.ssid;
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LEN {
+            self.report(
+                Severity::Error,
+                &join_path(path, "ssid"),
+                format!("SSID {ssid:?} should be 1 to {MAX_SSID_LEN} bytes long"),
+            );
+        }
+        // EAP passwords go to the EAP client instead, which has no such limits
+        let (Some(password), None) = (&network.password, &network.eap) else {
+            return;
+        };
+        let password_path = join_path(path, "password");
+        // WEP keys follow other rules, but still have to fit in the driver config
+        if matches!(
+            network.auth_method,
+            Some(AuthMethod::None | AuthMethod::WEP)
+        ) {
+            if password.len() > PSK_HEX_LEN {
+                self.report(
+                    Severity::Error,
+                    &password_path,
+                    format!(
+                        "Wifi password should be at most {PSK_HEX_LEN} bytes long, but it has {}",
+                        password.len()
+                    ),
+                );
+            }
+            return;
+        }
+        let is_psk =
+            password.len() == PSK_HEX_LEN && password.chars().all(|char| char.is_ascii_hexdigit());
+        if !is_psk && !(MIN_PASSPHRASE_LEN..=MAX_PASSPHRASE_LEN).contains(&password.len()) {
+            self.report(
+                Severity::Error,
+                &password_path,
+                format!(
+                    "Wifi password should be {MIN_PASSPHRASE_LEN} to {MAX_PASSPHRASE_LEN} \
+                    characters long, but it has {}",
+                    password.len()
+                ),
+            );
+        }
+    }
+
+    fn check_wifi(&mut self, root: &toml::Table) {
+        let Some(wifi) = root.get("wifi").and_then(|value| value.as_table()) else {
+            self.report(
+                Severity::Error,
+                "",
+                "Missing the [wifi] section".to_string(),
+            );
+            return;
+        };
+        let has_legacy_network = self.is_legacy() && wifi.contains_key("ssid");
+        if has_legacy_network {
+            self.check_network(wifi, "wifi");
+        }
+        if let Some(networks) = wifi.get("networks").and_then(|value| value.as_array()) {
+            for (index, network) in networks.iter().enumerate() {
+                if let Some(network) = network.as_table() {
+                    self.check_network(network, &format!("wifi.networks[{index}]"));
+                }
+            }
+        }
+        if !has_legacy_network && !wifi.contains_key("networks") {
+            self.report(
+                Severity::Error,
+                "wifi",
+                "No Wifi network configured, add [[wifi.networks]]".to_string(),
+            );
+        }
+    }
+
+    fn check_api(&mut self, root: &toml::Table) {
+        let Some(api) = root.get("api").and_then(|value| value.as_table()) else {
+            self.report(Severity::Error, "", "Missing the [api] section".to_string());
+            return;
+        };
+        self.check_enum::<ApiTransport>(api, "transport", "api");
+        let transport = api
+            .get("transport")
+            .and_then(|value| value.clone().try_into::<ApiTransport>().ok())
+            .unwrap_or_default();
+        if let (true, Some(url)) = (self.is_legacy(), get_str(api, "endpoint")) {
+            if let Err(message) = check_url(url, transport) {
+                self.report(Severity::Error, "api.endpoint", message);
+            }
+        }
+        if let Some(endpoints) = api.get("endpoints").and_then(|value| value.as_array()) {
+            for (index, endpoint) in endpoints.iter().enumerate() {
+                let path = format!("api.endpoints[{index}]");
+                match endpoint
+                    .as_table()
+                    .and_then(|endpoint| get_str(endpoint, "url"))
+                {
+                    Some(url) => {
+                        if let Err(message) = check_url(url, transport) {
+                            self.report(Severity::Error, &join_path(&path, "url"), message);
+                        }
+                    }
+                    None => self.report(
+                        Severity::Error,
+                        &path,
+                        "Missing url in [[api.endpoints]]".to_string(),
+                    ),
+                }
+            }
+        }
+    }
+
+    fn check_schedule(&mut self, root: &toml::Table) {
+        if let Some(schedule) = root.get("schedule").and_then(|value| value.as_table()) {
+            self.check_enum::<IdleMode>(schedule, "idle", "schedule");
+        }
+    }
+
+    fn check_log(&mut self, root: &toml::Table) {
+        let Some(log) = root.get("log").and_then(|value| value.as_table()) else {
+            return;
+        };
+        self.check_enum::<LogLevel>(log, "level", "log");
+        if let Some(targets) = log.get("targets").and_then(|value| value.as_table()) {
+            for target in targets.keys() {
+                self.check_enum::<LogLevel>(targets, target, "log.targets");
+            }
+        }
+    }
+
+    fn check_config(&mut self, config: &Config) {
+        for (index, network) in config.wifi.networks.iter().enumerate() {
+            self.check_network_config(network, &format!("wifi.networks[{index}]"));
+        }
+        if config.usb.as_ref().is_some_and(|usb| usb.high_speed) {
+            self.report(
+                Severity::Warning,
+                "usb.high_speed",
+                "USB high speed is not supported by the USB controller of ESP32-S3, it's ignored"
+                    .to_string(),
+            );
+        }
+        if let Some(sd) = &config.sd {
+            if sd.bus_width != 1 && sd.bus_width != 4 {
+                self.report(
+                    Severity::Error,
+                    "sd.bus_width",
+                    format!("SD bus_width should be 1 or 4, but it's {}", sd.bus_width),
+                );
+            }
+            if !(MIN_SD_FREQUENCY_KHZ..=MAX_SD_FREQUENCY_KHZ).contains(&sd.frequency_khz) {
+                self.report(
+                    Severity::Error,
+                    "sd.frequency_khz",
+                    format!(
+                        "SD frequency_khz should be {MIN_SD_FREQUENCY_KHZ} to \
+                        {MAX_SD_FREQUENCY_KHZ}, but it's {}",
+                        sd.frequency_khz
+                    ),
+                );
+            }
+        }
+        if let Some(schedule) = &config.schedule {
+            if let Err(error) = Schedule::new(schedule) {
+                self.report(
+                    Severity::Error,
+                    "schedule",
+                    format!("Invalid schedule: {error}"),
+                );
+            }
+        }
+    }
+}
+
+/// Check the config content for every problem we can find, rather than stopping at the first
+/// one like parsing does
+pub fn validate(content: &str, decrypt: &dyn Fn(&str) -> anyhow::Result<String>) -> Vec<Problem> {
+    let root: toml::Table = match content.parse() {
+        Ok(root) => root,
+        Err(error) => {
+            let error: toml::de::Error = error;
+            let line = error
+                .span()
+                .map(|span| content[..span.start].matches('\n').count() + 1);
+            return vec![Problem {
+                severity: Severity::Error,
+                line,
+                message: format!("Invalid TOML syntax: {}", error.message()),
+            }];
+        }
+    };
+    let mut validator = Validator {
+        line_index: LineIndex::new(content),
+        schema_version: CURRENT_SCHEMA_VERSION,
+        problems: vec![],
+    };
+    match schema_version(&root) {
+        Ok(version) => validator.schema_version = version,
+        Err(error) => validator.report(Severity::Error, "schema_version", format!("{error}")),
+    }
+    validator.check_keys(&root, CONFIG_FIELDS, "");
+    validator.check_wifi(&root);
+    validator.check_api(&root);
+    validator.check_schedule(&root);
+    validator.check_log(&root);
+    // Anything else, like wrong value types or values failing to decrypt
+    if !validator
+        .problems
+        .iter()
+        .any(|problem| problem.severity == Severity::Error)
+    {
+        match Config::parse(content, decrypt) {
+            Ok(config) => validator.check_config(&config),
+            Err(error) => validator.report(Severity::Error, "", format!("{error}")),
+        }
+    }
+    validator.problems
+}
+
+/// A human-readable report of the problems, for the user to read on the drive
+pub fn render_report(config_file_name: &str, problems: &[Problem]) -> String {
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    let warnings = problems.len() - errors;
+    let mut report = format!("SecureDash config check for {config_file_name}\n\n");
+    if errors == 0 {
+        report.push_str("The config was accepted");
+        if warnings > 0 {
+            report.push_str(&format!(", with {warnings} warning(s)"));
+        }
+        report.push_str(".\n");
+    } else {
+        report.push_str(&format!(
+            "The config was rejected, with {errors} error(s) and {warnings} warning(s).\n\
+            Please fix them and plug the drive back in.\n"
+        ));
+    }
+    if !problems.is_empty() {
+        report.push('\n');
+        for problem in problems {
+            report.push_str(&format!("- {problem}\n"));
+        }
+    }
+    report
+}
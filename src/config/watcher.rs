@@ -0,0 +1,39 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The modification time and size of the file, `None` if it can't be read
+type FileStamp = Option<(Option<SystemTime>, u64)>;
+
+/// Notices changes made to the config file by the USB host. The volume is only mounted to us
+/// while the host isn't using it, so besides polling the modification time and size, the file
+/// is looked at again whenever the volume is mounted back to us.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    stamp: FileStamp,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Self {
+        let mut watcher = Self {
+            path: path.to_path_buf(),
+            stamp: None,
+        };
+        watcher.stamp = watcher.read_stamp();
+        watcher
+    }
+
+    fn read_stamp(&self) -> FileStamp {
+        std::fs::metadata(&self.path)
+            .ok()
+            .map(|metadata| (metadata.modified().ok(), metadata.len()))
+    }
+
+    /// Whether the file may have changed since the last call. With `remounted`, it's always
+    /// considered changed, since the FAT timestamps set by the host can't be trusted.
+    pub fn has_changed(&mut self, remounted: bool) -> bool {
+        let stamp = self.read_stamp();
+        let changed = remounted || stamp != self.stamp;
+        self.stamp = stamp;
+        changed
+    }
+}
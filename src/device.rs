@@ -0,0 +1,8 @@
+use esp_idf_svc::sys::{esp, esp_efuse_mac_get_default};
+
+/// A stable id of this device derived from the factory MAC address burned into eFuse
+pub fn device_id() -> anyhow::Result<String> {
+    let mut mac = [0u8; 6];
+    esp!(unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) })?;
+    Ok(mac.iter().map(|byte| format!("{byte:02x}")).collect())
+}
@@ -0,0 +1,69 @@
+use anyhow::bail;
+use esp_idf_svc::sys::{mbedtls_base64_decode, mbedtls_base64_encode};
+
+pub fn base64_encode(data: &[u8]) -> anyhow::Result<String> {
+    let mut buf = vec![0u8; (data.len() + 2) / 3 * 4 + 1];
+    let mut written: usize = 0;
+    let result = unsafe {
+        mbedtls_base64_encode(
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut written,
+            data.as_ptr(),
+            data.len(),
+        )
+    };
+    if result != 0 {
+        bail!("Failed to encode base64 with error code {result}");
+    }
+    buf.truncate(written);
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Decode `%XX` escapes in a URL path. A `+` is kept as-is, as it's only a space in forms.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' if index + 2 < bytes.len() => {
+                let high = (bytes[index + 1] as char).to_digit(16);
+                let low = (bytes[index + 2] as char).to_digit(16);
+                if let (Some(high), Some(low)) = (high, low) {
+                    decoded.push((high * 16 + low) as u8);
+                    index += 3;
+                    continue;
+                }
+                decoded.push(b'%');
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Decode a query or `application/x-www-form-urlencoded` component, where `+` stands for a space
+pub fn form_decode(value: &str) -> String {
+    percent_decode(&value.replace('+', " "))
+}
+
+pub fn base64_decode(data: &str) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; data.len() / 4 * 3 + 3];
+    let mut written: usize = 0;
+    let result = unsafe {
+        mbedtls_base64_decode(
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut written,
+            data.as_ptr(),
+            data.len(),
+        )
+    };
+    if result != 0 {
+        bail!("Failed to decode base64 with error code {result}");
+    }
+    buf.truncate(written);
+    Ok(buf)
+}
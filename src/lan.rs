@@ -0,0 +1,2 @@
+pub mod mdns;
+pub mod server;
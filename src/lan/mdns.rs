@@ -0,0 +1,112 @@
+use crate::config::Endpoint;
+use esp_idf_svc::mdns::{EspMdns, Interface, Protocol, QueryResult};
+use futures::channel::oneshot;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const HOSTNAME_PREFIX: &str = "securedash";
+/// The service type this device advertises itself with
+const DEVICE_SERVICE_TYPE: &str = "_securedash-dev";
+/// The service type the archival servers advertise themselves with
+const SERVER_SERVICE_TYPE: &str = "_securedash";
+const SERVICE_PROTO: &str = "_tcp";
+const MAX_QUERY_RESULTS: usize = 8;
+const DISCOVERY_STACK_SIZE: usize = 6144;
+
+pub struct Mdns {
+    mdns: EspMdns,
+}
+
+impl Mdns {
+    pub fn start(device_id: &str, version: &str, http_port: Option<u16>) -> anyhow::Result<Self> {
+        let mut mdns = EspMdns::take()?;
+        let hostname = format!("{HOSTNAME_PREFIX}-{device_id}");
+        mdns.set_hostname(&hostname)?;
+        mdns.set_instance_name(&format!("SecureDash {device_id}"))?;
+        let http_port_str = http_port.map(|port| port.to_string());
+        let mut txt: Vec<(&str, &str)> = vec![("id", device_id), ("version", version)];
+        if let Some(http_port_str) = &http_port_str {
+            txt.push(("http_port", http_port_str));
+        }
+        mdns.add_service(
+            None,
+            DEVICE_SERVICE_TYPE,
+            SERVICE_PROTO,
+            http_port.unwrap_or(0),
+            &txt,
+        )?;
+        log::info!("Advertising mDNS as {hostname}.local");
+        Ok(Self { mdns })
+    }
+
+    /// Look up `_securedash._tcp` services on the LAN and turn them into endpoints. The URL is
+    /// built from the `scheme` (default `ws`) and `path` (default `/`) TXT records.
+    pub fn discover(&mut self, timeout: Duration) -> anyhow::Result<Vec<Endpoint>> {
+        let mut results: Vec<QueryResult> = (0..MAX_QUERY_RESULTS)
+            .map(|_| QueryResult {
+                instance_name: None,
+                hostname: None,
+                port: 0,
+                txt: vec![],
+                addr: vec![],
+                interface: Interface::STA,
+                ip_protocol: Protocol::V4,
+            })
+            .collect();
+        let count = self.mdns.query_ptr(
+            SERVER_SERVICE_TYPE,
+            SERVICE_PROTO,
+            timeout,
+            MAX_QUERY_RESULTS,
+            &mut results,
+        )?;
+        let mut endpoints = vec![];
+        for result in &results[..count] {
+            let Some(addr) = result.addr.iter().find(|addr| addr.is_ipv4()) else {
+                continue;
+            };
+            let txt_value = |key: &str| {
+                result
+                    .txt
+                    .iter()
+                    .find(|(txt_key, _)| txt_key == key)
+                    .map(|(_, value)| value.clone())
+            };
+            let scheme = txt_value("scheme").unwrap_or("ws".to_string());
+            let path = txt_value("path").unwrap_or("/".to_string());
+            let host = match addr {
+                IpAddr::V4(addr) => addr.to_string(),
+                IpAddr::V6(addr) => format!("[{addr}]"),
+            };
+            let url = format!("{scheme}://{host}:{}{path}", result.port);
+            log::info!(
+                "Discovered server {:?} at {url}",
+                result.instance_name.as_deref().unwrap_or("<Unknown>")
+            );
+            endpoints.push(Endpoint {
+                url,
+                username: None,
+                password: None,
+                token: None,
+            });
+        }
+        Ok(endpoints)
+    }
+}
+
+/// Discover on a thread of its own, as the query blocks for the whole timeout and the executor
+/// has to keep running meanwhile
+pub async fn discover_in_background(
+    mdns: Arc<Mutex<Mdns>>,
+    timeout: Duration,
+) -> anyhow::Result<Vec<Endpoint>> {
+    let (sender, receiver) = oneshot::channel();
+    thread::Builder::new()
+        .stack_size(DISCOVERY_STACK_SIZE)
+        .spawn(move || {
+            let _ = sender.send(mdns.lock().unwrap().discover(timeout));
+        })?;
+    receiver.await?
+}
@@ -0,0 +1,280 @@
+use crate::api::processor::{
+    resolve_path, Command, CommandRequest, CommandResponse, Processor, Response,
+};
+use crate::config::Http;
+use crate::encoding::{base64_encode, percent_decode};
+use anyhow::{anyhow, bail};
+use embedded_svc::http::Method;
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::hal::task::block_on;
+use esp_idf_svc::http::server::ws::EspHttpWsConnection;
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::io::Write;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const FILES_PREFIX: &str = "/files";
+const STACK_SIZE: usize = 10240;
+const DOWNLOAD_BUFFER_SIZE: usize = 4096;
+const MAX_WS_FRAME_SIZE: usize = 4096;
+
+#[derive(Debug, Deserialize)]
+struct AuthRequest {
+    username: String,
+    password: String,
+}
+
+/// An optional HTTP server on the device for accessing the files and commands directly from the
+/// LAN. All routes are protected with basic auth, except the WebSocket route which requires an
+/// auth frame like `{"username": "...", "password": "..."}` first, as browsers cannot set
+/// headers on WebSocket connections.
+pub struct LanServer {
+    _server: EspHttpServer<'static>,
+}
+
+struct Auth {
+    username: String,
+    password: String,
+    expected_header: String,
+}
+
+impl Auth {
+    fn new(config: &Http) -> anyhow::Result<Self> {
+        Ok(Self {
+            username: config.username.clone(),
+            password: config.password.clone(),
+            expected_header: format!(
+                "Basic {}",
+                base64_encode(format!("{}:{}", config.username, config.password).as_bytes())?
+            ),
+        })
+    }
+
+    fn check(&self, request: &Request<&mut EspHttpConnection>) -> bool {
+        request.header("Authorization").is_some_and(|header| {
+            constant_time_eq(header.as_bytes(), self.expected_header.as_bytes())
+        })
+    }
+}
+
+/// Compare without bailing out at the first difference, so that the time taken doesn't tell how
+/// much of a guessed credential is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn reject(request: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
+    request.into_response(
+        401,
+        Some("Unauthorized"),
+        &[("WWW-Authenticate", "Basic realm=\"securedash\"")],
+    )?;
+    Ok(())
+}
+
+/// Parse a `Range: bytes=start-end` header into an inclusive range within the file size
+fn parse_range(header: &str, file_size: u64) -> Option<(u64, u64)> {
+    if file_size == 0 {
+        return None;
+    }
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (file_size.saturating_sub(suffix), file_size - 1)
+        }
+        (start, "") => (start.parse().ok()?, file_size - 1),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(file_size - 1),
+        ),
+    };
+    if start > end || end >= file_size {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn serve_download(request: Request<&mut EspHttpConnection>, path: &Path) -> anyhow::Result<()> {
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let range = request
+        .header("Range")
+        .and_then(|header| parse_range(header, file_size));
+    let (start, end) = range.unwrap_or((0, file_size.saturating_sub(1)));
+    let length = if file_size == 0 { 0 } else { end - start + 1 };
+    let content_length = length.to_string();
+    let content_range = format!("bytes {start}-{end}/{file_size}");
+    let mut headers = vec![
+        ("Content-Type", "application/octet-stream"),
+        ("Content-Length", content_length.as_str()),
+        ("Accept-Ranges", "bytes"),
+    ];
+    let status = if range.is_some() {
+        headers.push(("Content-Range", content_range.as_str()));
+        206
+    } else {
+        200
+    };
+    log::info!("Serving {path:?}, range={range:?}, length={length}");
+    let mut response = request.into_response(status, None, &headers)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut buf = vec![0; DOWNLOAD_BUFFER_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let read_size =
+            file.read(&mut buf[..remaining.min(DOWNLOAD_BUFFER_SIZE as u64) as usize])?;
+        if read_size == 0 {
+            break;
+        }
+        response.write_all(&buf[..read_size])?;
+        remaining -= read_size as u64;
+    }
+    Ok(())
+}
+
+fn serve_listing(
+    request: Request<&mut EspHttpConnection>,
+    processor: &Processor,
+    path: &str,
+) -> anyhow::Result<()> {
+    let mut body: Option<anyhow::Result<String>> = None;
+    // The server threads are not on the executor, so they can wait for the command here
+    block_on(processor.process(
+        &CommandRequest {
+            id: "lan".to_string(),
+            command: Command::ListFiles {
+                path: path.to_string(),
+            },
+        },
+        |response: CommandResponse| {
+            body = Some(match response.response {
+                Response::Error { message } => Err(anyhow!(message)),
+                response => Ok(serde_json::to_string(&response)?),
+            });
+            Ok(())
+        },
+    ))?;
+    match body.ok_or_else(|| anyhow!("No response"))? {
+        Ok(body) => {
+            request
+                .into_response(200, None, &[("Content-Type", "application/json")])?
+                .write_all(body.as_bytes())?;
+        }
+        Err(error) => {
+            request
+                .into_status_response(404)?
+                .write_all(error.to_string().as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_ws(
+    ws: &mut EspHttpWsConnection,
+    processor: &Processor,
+    auth: &Auth,
+    authenticated: &Mutex<HashSet<i32>>,
+) -> anyhow::Result<()> {
+    let session = ws.session();
+    if ws.is_new() {
+        log::info!("LAN WebSocket session {session} opened");
+        return Ok(());
+    }
+    if ws.is_closed() {
+        log::info!("LAN WebSocket session {session} closed");
+        authenticated.lock().unwrap().remove(&session);
+        return Ok(());
+    }
+    let (_frame_type, len) = ws.recv(&mut [])?;
+    if len > MAX_WS_FRAME_SIZE {
+        ws.send(FrameType::Close, &[])?;
+        bail!("Frame too large: {len}");
+    }
+    let mut buf = vec![0; len];
+    ws.recv(&mut buf)?;
+    // Frames are NUL terminated
+    let text = std::str::from_utf8(&buf)?.trim_end_matches('\0');
+
+    if !authenticated.lock().unwrap().contains(&session) {
+        let is_valid = serde_json::from_str::<AuthRequest>(text)
+            .map(|request| {
+                // Both are compared, so that the time taken doesn't tell which one is wrong
+                constant_time_eq(request.username.as_bytes(), auth.username.as_bytes())
+                    & constant_time_eq(request.password.as_bytes(), auth.password.as_bytes())
+            })
+            .unwrap_or(false);
+        if !is_valid {
+            log::warn!("LAN WebSocket session {session} failed to authenticate");
+            ws.send(FrameType::Close, &[])?;
+            return Ok(());
+        }
+        authenticated.lock().unwrap().insert(session);
+        return Ok(());
+    }
+
+    let request: CommandRequest = serde_json::from_str(text)?;
+    log::info!("Processing LAN request {:?}", request);
+    block_on(processor.process(&request, |response: CommandResponse| {
+        match response.response {
+            Response::FetchFileChunk { .. } => {
+                ws.send(FrameType::Binary(false), &rmp_serde::to_vec(&response)?)?
+            }
+            _ => ws.send(
+                FrameType::Text(false),
+                serde_json::to_string(&response)?.as_bytes(),
+            )?,
+        }
+        Ok(())
+    }))
+}
+
+impl LanServer {
+    pub fn start(config: &Http, processor: Processor) -> anyhow::Result<Self> {
+        let mut server = EspHttpServer::new(&Configuration {
+            http_port: config.port,
+            stack_size: STACK_SIZE,
+            uri_match_wildcard: true,
+            ..Default::default()
+        })?;
+        let processor = Arc::new(processor);
+        let auth = Arc::new(Auth::new(config)?);
+
+        let captured_processor = processor.clone();
+        let captured_auth = auth.clone();
+        server.fn_handler(
+            &format!("{FILES_PREFIX}/*"),
+            Method::Get,
+            move |request| -> anyhow::Result<()> {
+                if !captured_auth.check(&request) {
+                    return reject(request);
+                }
+                let uri = request.uri().to_string();
+                let uri_path = uri
+                    .split('?')
+                    .next()
+                    .unwrap_or("")
+                    .trim_start_matches(FILES_PREFIX);
+                let relative = percent_decode(uri_path.trim_start_matches('/'));
+                let path = resolve_path(&captured_processor.root_dir, &relative)?;
+                if path.is_dir() {
+                    serve_listing(request, &captured_processor, &relative)
+                } else {
+                    serve_download(request, &path)
+                }
+            },
+        )?;
+
+        let authenticated = Arc::new(Mutex::new(HashSet::new()));
+        server.ws_handler("/ws", move |ws| -> anyhow::Result<()> {
+            handle_ws(ws, &processor, &auth, &authenticated)
+        })?;
+
+        log::info!("LAN HTTP server started on port {}", config.port);
+        Ok(Self { _server: server })
+    }
+}
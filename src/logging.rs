@@ -0,0 +1,67 @@
+use crate::config::{Log, LogLevel};
+use esp_idf_svc::sys::{
+    esp_log_level_set, esp_log_level_t, esp_log_level_t_ESP_LOG_DEBUG,
+    esp_log_level_t_ESP_LOG_ERROR, esp_log_level_t_ESP_LOG_INFO, esp_log_level_t_ESP_LOG_NONE,
+    esp_log_level_t_ESP_LOG_VERBOSE, esp_log_level_t_ESP_LOG_WARN, CONFIG_LOG_DEFAULT_LEVEL,
+};
+use log::LevelFilter;
+use std::ffi::CString;
+
+const ALL_TARGETS: &str = "*";
+
+fn default_level() -> LogLevel {
+    match CONFIG_LOG_DEFAULT_LEVEL {
+        0 => LogLevel::Off,
+        1 => LogLevel::Error,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Info,
+        4 => LogLevel::Debug,
+        _ => LogLevel::Trace,
+    }
+}
+
+fn esp_log_level(level: LogLevel) -> esp_log_level_t {
+    match level {
+        LogLevel::Off => esp_log_level_t_ESP_LOG_NONE,
+        LogLevel::Error => esp_log_level_t_ESP_LOG_ERROR,
+        LogLevel::Warn => esp_log_level_t_ESP_LOG_WARN,
+        LogLevel::Info => esp_log_level_t_ESP_LOG_INFO,
+        LogLevel::Debug => esp_log_level_t_ESP_LOG_DEBUG,
+        LogLevel::Trace => esp_log_level_t_ESP_LOG_VERBOSE,
+    }
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn set_level(target: &str, level: LogLevel) -> anyhow::Result<()> {
+    let target = CString::new(target)?;
+    unsafe { esp_log_level_set(target.as_ptr(), esp_log_level(level)) };
+    Ok(())
+}
+
+/// Apply the configured log levels, or go back to the defaults of the build without a `[log]`
+/// section. `EspLogger` looks up the ESP-IDF level of the target for every record, so that's
+/// where the levels go, while the max level of the `log` crate is raised to let them through.
+/// Levels can't go beyond the maximum level the firmware was built with.
+pub fn apply_log_config(log: Option<&Log>) -> anyhow::Result<()> {
+    let level = log.and_then(|log| log.level).unwrap_or_else(default_level);
+    // The wildcard also clears the levels previously set for specific targets
+    set_level(ALL_TARGETS, level)?;
+    let mut max_level = level_filter(level);
+    for (target, target_level) in log.iter().flat_map(|log| log.targets.iter()) {
+        set_level(target, *target_level)?;
+        max_level = max_level.max(level_filter(*target_level));
+    }
+    log::set_max_level(max_level);
+    log::info!("Log level set to {level:?}, max_level={max_level}");
+    Ok(())
+}
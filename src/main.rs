@@ -2,37 +2,78 @@ mod api;
 mod benchmarks;
 mod config;
 mod debug;
+mod device;
+mod encoding;
+mod lan;
+mod logging;
+mod monitor;
+mod provisioning;
+mod schedule;
+mod secrets;
 mod storage;
 mod usb;
 mod wifi;
 
-use crate::api::processor::{process_events, DeviceInfo, DeviceInfoProducer};
-use crate::api::websocket::{ConnectionState, SessionEvent, WebSocketSession};
+use crate::api::http::HttpSession;
+use crate::api::mqtt::MqttSession;
+use crate::api::outbox::Outbox;
+use crate::api::processor::{
+    supervise_events, ApiConnectedAt, ApiReloadSignal, DeviceInfo, DeviceInfoProducer, Processor,
+    SessionFactory, WifiNetworkInfo, WifiScanner,
+};
+use crate::api::transport::Transport;
+use crate::api::websocket::WebSocketSession;
 use crate::benchmarks::storage::StorageBenchmark;
-use crate::config::{Config, Wifi};
+use crate::config::editor::ConfigEditor;
+use crate::config::migrate::{migrate, CURRENT_SCHEMA_VERSION};
+use crate::config::paths::Paths;
+use crate::config::store::ConfigStore;
+use crate::config::validate::{render_report, validate, Severity};
+use crate::config::watcher::ConfigWatcher;
+use crate::config::{ApiTransport, Config, ConfigSource, SharedConfigSource, Wifi};
 use crate::debug::CardInfo;
-use crate::storage::sd_card::{SDCardPeripherals, SDCardStorage};
+use crate::device::device_id;
+use crate::lan::mdns::{discover_in_background, Mdns};
+use crate::lan::server::LanServer;
+use crate::logging::apply_log_config;
+use crate::monitor::VolumeMonitor;
+use crate::provisioning::portal::ProvisioningPortal;
+use crate::schedule::Schedule;
+use crate::secrets::SecretKey;
+use crate::storage::sd_card::{BusWidth, SDCardPeripherals, SDCardSettings, SDCardStorage};
 use crate::storage::spiflash::SPIFlashStorage;
-use crate::usb::msc_device::{MSCDevice, MSCDeviceConfig};
-use crate::wifi::session::{WifiConfig, WifiSession};
+use crate::usb::msc_device::{usb_speed, MSCDevice, MSCDeviceConfig, Storage};
+use crate::wifi::link::link_quality;
+use crate::wifi::session::{EapConfig, IpConfig, SharedWifiSession, WifiConfig, WifiSession};
+use crate::wifi::status::{SharedWifiStatus, WifiState, WifiStateSignal};
+use crate::wifi::supervisor::{WifiReloadSignal, WifiSupervisor};
+use anyhow::{anyhow, Context};
+use embassy_sync::mutex::Mutex;
 use embedded_svc::wifi::AuthMethod;
 use embedded_svc::ws::FrameType;
 use esp_idf_svc::hal::gpio::{Gpio10, PinDriver, Pull};
+use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::hal::peripheral::Peripheral;
 use esp_idf_svc::hal::prelude::Peripherals;
+use esp_idf_svc::hal::reset::restart;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::sntp::EspSntp;
 use esp_idf_svc::sys::{esp, esp_vfs_fat_info, free, sdmmc_card_t};
 use esp_idf_svc::timer::EspTimerService;
 use futures::executor::{LocalPool, LocalSpawner};
+use futures::future::{pending, select, Either, FutureExt};
 use futures::task::LocalSpawnExt;
+use std::cell::RefCell;
 use std::ffi::CString;
 use std::fmt::{Debug, Formatter};
 use std::fs::read_dir;
 use std::ops::Deref;
 use std::path::Path;
+use std::pin::pin;
 use std::ptr::replace;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use std::{fmt, thread};
 use time::OffsetDateTime;
 
@@ -41,26 +82,201 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const CONFIG_PATH: Option<&str> = option_env!("CONFIG_PATH");
 const DEFAULT_CONFIG_PATH: &str = "securedash.toml";
+/// The config validation report, written next to the config file
+const STATUS_FILE_NAME: &str = "securedash-status.txt";
+/// Set to `true` in the config to rewrite the file once it's migrated to the current schema
+const WRITE_MIGRATED_KEY: &str = "write_migrated";
 const PARTITION_LABEL: Option<&str> = option_env!("PARTITION_LABEL");
 const DEFAULT_PARTITION_LABEL: &str = "storage";
 const MOUNT_PATH: Option<&str> = option_env!("MOUNT_PATH");
 const DEFAULT_MOUNT_PATH: &str = "/disk";
+const OUTBOX_MAX_EVENTS: usize = 32;
+const LOW_SPACE_RATIO: f64 = 0.05;
+const MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long Wi-Fi gets to connect after a remote config update changed it, before the previous
+/// config is restored
+const CONFIG_ROLLBACK_TIMEOUT: Duration = Duration::from_secs(120);
+const UNKNOWN_DEVICE_ID: &str = "unknown";
 
-fn load_config(config_file: &str) -> Option<Config> {
-    log::info!("Reading config from {}", config_file);
-    let config = Config::read(&config_file);
-    if let Err(error) = &config {
-        log::warn!("Failed to load config: {error}");
-        // TODO: keep reading if encounter "No such device (os error 19)"?
-        //       this means file system not formatted.
-        //       maybe if the user formatted and put a config file into the folder, we should
-        //       make it connects to their wifi and API immediately?
-    }
-    let config = config.ok();
-    if let Some(config) = &config {
-        log::info!("Loaded config: {config:#?}");
+/// The config in use, along with where it came from and its original content
+struct LoadedConfig {
+    config: Config,
+    source: ConfigSource,
+    content: String,
+}
+
+/// The config to go back to if Wi-Fi or the API doesn't connect after a remote update changed it
+struct PendingRollback {
+    content: String,
+    applied_at: Instant,
+    wifi_changed: bool,
+    api_changed: bool,
+}
+
+fn decrypt_value(secret_key: Option<&SecretKey>, value: &str) -> anyhow::Result<String> {
+    secret_key
+        .ok_or_else(|| anyhow!("No secret key provisioned to decrypt the encrypted values"))?
+        .decrypt(value)
+}
+
+fn parse_config(content: &str, secret_key: Option<&SecretKey>) -> anyhow::Result<Config> {
+    Config::parse(content, &|value| decrypt_value(secret_key, value))
+}
+
+/// Migrate the config to the current schema version. The file is only rewritten if it asks for
+/// it with `write_migrated = true`, as comments and formatting are lost, and the original is kept
+/// as `<name>.v<version>.bak`. Returns the content to use, along with a note for the report.
+fn migrate_config_file(
+    config_file: &Path,
+    config_file_name: &str,
+    content: String,
+) -> anyhow::Result<(String, Option<String>)> {
+    let mut table: toml::Table = content.parse()?;
+    let write_migrated = table
+        .get(WRITE_MIGRATED_KEY)
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false);
+    let version = migrate(&mut table)?;
+    if version == CURRENT_SCHEMA_VERSION {
+        return Ok((content, None));
+    }
+    log::info!("Config is in schema version {version}, migrated to {CURRENT_SCHEMA_VERSION}");
+    if !write_migrated {
+        return Ok((
+            content,
+            Some(format!(
+                "The config uses the older schema_version {version}, and it's upgraded to \
+                {CURRENT_SCHEMA_VERSION} when loaded. Add write_migrated = true to upgrade the \
+                file as well."
+            )),
+        ));
+    }
+    let backup_name = format!("{config_file_name}.v{version}.bak");
+    let result = toml::to_string(&table)
+        .map_err(anyhow::Error::from)
+        .and_then(|migrated| {
+            std::fs::write(config_file.with_file_name(&backup_name), &content)?;
+            std::fs::write(config_file, &migrated)?;
+            Ok(migrated)
+        });
+    match result {
+        Ok(migrated) => {
+            log::info!("Wrote migrated config to {config_file:?}, backup={backup_name}");
+            Ok((
+                migrated,
+                Some(format!(
+                    "The config file was upgraded from schema_version {version} to \
+                    {CURRENT_SCHEMA_VERSION}, and the original is kept as {backup_name}."
+                )),
+            ))
+        }
+        Err(error) => {
+            log::warn!("Failed to write migrated config with error: {error:#}");
+            Ok((
+                content,
+                Some(format!("Failed to upgrade the config file: {error}")),
+            ))
+        }
     }
-    config
+}
+
+/// Read and validate the config file, writing the validation report next to it so that the user
+/// can see what's wrong without a serial console
+fn read_config_file(config_file: &Path, secret_key: Option<&SecretKey>) -> anyhow::Result<String> {
+    let status_file = config_file.with_file_name(STATUS_FILE_NAME);
+    let config_file_name = config_file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (content, report) = match std::fs::read_to_string(config_file) {
+        Ok(content) => {
+            let problems = validate(&content, &|value| decrypt_value(secret_key, value));
+            for problem in &problems {
+                log::warn!("Config problem: {problem}");
+            }
+            let mut report = render_report(&config_file_name, &problems);
+            let errors = problems
+                .iter()
+                .filter(|problem| problem.severity == Severity::Error)
+                .count();
+            let content = if errors == 0 {
+                migrate_config_file(config_file, &config_file_name, content).map(
+                    |(content, note)| {
+                        if let Some(note) = note {
+                            report.push_str(&format!("\n{note}\n"));
+                        }
+                        content
+                    },
+                )
+            } else {
+                Err(anyhow!("Config has {errors} error(s)"))
+            };
+            (content, report)
+        }
+        Err(error) => {
+            let report = format!(
+                "SecureDash config check for {config_file_name}\n\n\
+                The config could not be read: {error}\n"
+            );
+            (Err(error.into()), report)
+        }
+    };
+    if let Err(error) = std::fs::write(&status_file, report) {
+        log::warn!("Failed to write config status to {status_file:?} with error: {error}");
+    }
+    content
+}
+
+/// Load the config from the file, or fall back to the last known-good one in NVS
+fn load_config(
+    config_file: &Path,
+    secret_key: Option<&SecretKey>,
+    config_store: Option<&ConfigStore>,
+) -> Option<LoadedConfig> {
+    log::info!("Reading config from {config_file:?}");
+    let loaded = read_config_file(config_file, secret_key).and_then(|content| {
+        Ok(LoadedConfig {
+            config: parse_config(&content, secret_key)?,
+            source: ConfigSource::File,
+            content,
+        })
+    });
+    let loaded = match loaded {
+        Ok(loaded) => Some(loaded),
+        Err(error) => {
+            log::warn!("Failed to load config: {error}");
+            // TODO: keep reading if encounter "No such device (os error 19)"?
+            //       this means file system not formatted.
+            let content = config_store.map(|store| store.load()).transpose();
+            match content {
+                Ok(Some(Some(content))) => match parse_config(&content, secret_key) {
+                    Ok(config) => Some(LoadedConfig {
+                        config,
+                        source: ConfigSource::Nvs,
+                        content,
+                    }),
+                    Err(error) => {
+                        log::warn!("Failed to load config from NVS: {error}");
+                        None
+                    }
+                },
+                Ok(_) => None,
+                Err(error) => {
+                    log::warn!("Failed to read config from NVS: {error}");
+                    None
+                }
+            }
+        }
+    };
+    if let Some(loaded) = &loaded {
+        log::info!(
+            "Loaded config from {:?}: {:#?}",
+            loaded.source,
+            loaded.config
+        );
+    }
+    loaded
 }
 
 impl From<&config::AuthMethod> for AuthMethod {
@@ -69,84 +285,680 @@ impl From<&config::AuthMethod> for AuthMethod {
     }
 }
 
+impl From<&config::Sd> for SDCardSettings {
+    fn from(value: &config::Sd) -> Self {
+        Self {
+            bus_width: match value.bus_width {
+                1 => BusWidth::One,
+                _ => BusWidth::Four,
+            },
+            frequency_khz: value.frequency_khz,
+            internal_pullups: value.internal_pullups,
+        }
+    }
+}
+
+impl From<&config::Usb> for MSCDeviceConfig {
+    fn from(value: &config::Usb) -> Self {
+        Self {
+            read_only: value.read_only,
+        }
+    }
+}
+
+/// Read a certificate or key file from the volume. PEM data gets a NUL terminator appended, as
+/// required by mbedtls.
+fn read_cert(mount_path: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+    let file_path = Path::new(mount_path).join(path);
+    let mut data =
+        std::fs::read(&file_path).with_context(|| format!("Failed to read {file_path:?}"))?;
+    if data.starts_with(b"-----") {
+        data.push(0);
+    }
+    Ok(data)
+}
+
+fn load_eap_config(mount_path: &str, eap: &config::Eap) -> anyhow::Result<EapConfig> {
+    let read = |path: &Option<String>| {
+        path.as_deref()
+            .map(|path| read_cert(mount_path, path))
+            .transpose()
+    };
+    Ok(EapConfig {
+        identity: eap.identity.clone(),
+        anonymous_identity: eap.anonymous_identity.clone(),
+        username: eap.username.clone(),
+        ca_cert: read(&eap.ca_cert)?,
+        client_cert: read(&eap.client_cert)?,
+        client_key: read(&eap.client_key)?,
+        client_key_password: eap.client_key_password.clone(),
+    })
+}
+
+/// The Wi-Fi networks from the config. Networks with EAP certificates failing to load are
+/// skipped, so that the others can still be used.
+fn wifi_configs(wifi: &Wifi, mount_path: &str) -> Vec<WifiConfig> {
+    wifi.networks()
+        .iter()
+        .filter_map(|network| {
+            let eap = network
+                .eap
+                .as_ref()
+                .map(|eap| load_eap_config(mount_path, eap))
+                .transpose();
+            match eap {
+                Ok(eap) => Some(WifiConfig {
+                    ssid: network.ssid.clone(),
+                    password: network.password.clone(),
+                    auth_method: network.auth_method.as_ref().map(AuthMethod::from),
+                    priority: network.priority,
+                    hidden: network.hidden,
+                    eap,
+                }),
+                Err(error) => {
+                    log::error!(
+                        "Skipped network {} as its EAP config failed to load with error: {error:#}",
+                        network.ssid
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn ip_config(wifi: &Wifi) -> IpConfig {
+    wifi.ip
+        .as_ref()
+        .map(|ip| IpConfig {
+            address: ip.address,
+            gateway: ip.gateway,
+            netmask: ip.netmask,
+            dns: ip.dns.clone(),
+            hostname: ip.hostname.clone(),
+        })
+        .unwrap_or_default()
+}
+
+fn new_wifi_session(
+    config: &Config,
+    mount_path: &str,
+    modem: Modem,
+    nvs: Option<EspDefaultNvsPartition>,
+) -> anyhow::Result<SharedWifiSession> {
+    Ok(Arc::new(Mutex::new(WifiSession::new(
+        &wifi_configs(&config.wifi, mount_path),
+        &ip_config(&config.wifi),
+        modem,
+        nvs,
+    )?)))
+}
+
+fn new_device_info_producer(
+    paths: &Paths,
+    wifi_status: SharedWifiStatus,
+    config_source: SharedConfigSource,
+) -> anyhow::Result<DeviceInfoProducer> {
+    let captured_paths = paths.clone();
+    let mount_path_c_str = CString::new(paths.mount_path.as_bytes())?;
+    Ok(Box::new(move || {
+        let mut total_volume_size: u64 = 0;
+        let mut free_volume_size: u64 = 0;
+        esp!(unsafe {
+            esp_vfs_fat_info(
+                mount_path_c_str.as_ptr(),
+                &mut total_volume_size,
+                &mut free_volume_size,
+            )
+        })?;
+        let wifi_status = wifi_status.read().unwrap().clone();
+        let link_quality = wifi_status.ip.and_then(|_| link_quality().ok());
+        Ok(DeviceInfo {
+            version: VERSION.to_string(),
+            wifi_ip: wifi_status.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            wifi_ssid: wifi_status.ssid,
+            wifi_gateway: wifi_status.gateway.map(|gateway| gateway.to_string()),
+            wifi_netmask: wifi_status.netmask.map(|netmask| netmask.to_string()),
+            wifi_dns: wifi_status.dns.iter().map(|dns| dns.to_string()).collect(),
+            wifi_hostname: wifi_status.hostname,
+            wifi_rssi: link_quality.as_ref().map(|link_quality| link_quality.rssi),
+            wifi_channel: link_quality
+                .as_ref()
+                .map(|link_quality| link_quality.channel),
+            wifi_phy_mode: link_quality
+                .and_then(|link_quality| link_quality.phy_mode)
+                .map(|phy_mode| phy_mode.to_string()),
+            local_time: OffsetDateTime::now_utc(),
+            partition_label: captured_paths.partition_label.clone(),
+            mount_path: captured_paths.mount_path.clone(),
+            config_path: captured_paths.config_path.clone(),
+            usb_speed: usb_speed().map(|speed| speed.to_string()),
+            total_volume_size,
+            free_volume_size,
+            api_endpoint: None,
+            config_source: *config_source.read().unwrap(),
+        })
+    }))
+}
+
+fn new_wifi_scanner(wifi: SharedWifiSession) -> WifiScanner {
+    Arc::new(move || {
+        let wifi = wifi.clone();
+        async move {
+            // Waits for the supervisor to finish connecting, which holds the lock meanwhile
+            let access_points = wifi.lock().await.scan().await?;
+            Ok(access_points
+                .iter()
+                .map(|ap| WifiNetworkInfo {
+                    ssid: ap.ssid.to_string(),
+                    bssid: ap
+                        .bssid
+                        .iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect::<Vec<_>>()
+                        .join(":"),
+                    channel: ap.channel,
+                    rssi: ap.signal_strength,
+                    auth_method: ap.auth_method.map(|auth_method| format!("{auth_method:?}")),
+                })
+                .collect())
+        }
+        .boxed_local()
+    })
+}
+
+/// The parts of the running firmware that a changed config can be applied to without a restart
+struct Reloadable {
+    wifi: Option<SharedWifiSession>,
+    wifi_reload: Rc<WifiReloadSignal>,
+    api_config: Rc<RefCell<config::Api>>,
+    api_reload: Rc<ApiReloadSignal>,
+}
+
+/// Apply only the parts of the new config which changed. The others are logged and take effect
+/// after a restart.
+async fn apply_config_changes(
+    current: &Config,
+    new: &Config,
+    mount_path: &str,
+    reloadable: &Reloadable,
+) {
+    if new.wifi != current.wifi {
+        if let Some(wifi) = &reloadable.wifi {
+            log::info!("Wifi config changed, reconfiguring");
+            let result = wifi
+                .lock()
+                .await
+                .reconfigure(&wifi_configs(&new.wifi, mount_path), &ip_config(&new.wifi))
+                .await;
+            match result {
+                Ok(()) => reloadable.wifi_reload.signal(()),
+                Err(error) => log::error!("Failed to reconfigure wifi with error: {error:#}"),
+            }
+        }
+    }
+    if new.api != current.api {
+        log::info!("API config changed, starting a new session");
+        *reloadable.api_config.borrow_mut() = new.api.clone();
+        reloadable.api_reload.signal(());
+    }
+    if new.log != current.log {
+        if let Err(error) = apply_log_config(new.log.as_ref()) {
+            log::error!("Failed to apply log config with error: {error:#}");
+        }
+    }
+    let restart_required = [
+        ("http", new.http != current.http),
+        ("usb", new.usb != current.usb),
+        ("sd", new.sd != current.sd),
+        ("schedule", new.schedule != current.schedule),
+    ];
+    for (section, _) in restart_required.iter().filter(|(_, changed)| *changed) {
+        log::warn!("Changes to [{section}] take effect after a restart");
+    }
+}
+
 async fn run_async(spawner: LocalSpawner) -> Result<(), anyhow::Error> {
-    let partition_label = PARTITION_LABEL.unwrap_or(DEFAULT_PARTITION_LABEL);
-    let mount_path = MOUNT_PATH.unwrap_or(DEFAULT_MOUNT_PATH);
-    let config_path = CONFIG_PATH.unwrap_or(DEFAULT_CONFIG_PATH);
-    log::info!("Start {PKG_NAME} - version={VERSION}, partition_label={partition_label}, mount_path={mount_path}, config_path={config_path}");
+    // Only one can be taken, so take it once here and share it
+    let nvs = EspDefaultNvsPartition::take()
+        .map_err(|error| log::error!("Failed to take NVS partition with error: {error:#}"))
+        .ok();
+    let paths = Paths::load(
+        Paths {
+            partition_label: PARTITION_LABEL
+                .unwrap_or(DEFAULT_PARTITION_LABEL)
+                .to_string(),
+            mount_path: MOUNT_PATH.unwrap_or(DEFAULT_MOUNT_PATH).to_string(),
+            config_path: CONFIG_PATH.unwrap_or(DEFAULT_CONFIG_PATH).to_string(),
+        },
+        nvs.clone(),
+    );
+    let mount_path = paths.mount_path.as_str();
+    log::info!(
+        "Start {PKG_NAME} - version={VERSION}, partition_label={}, mount_path={mount_path}, config_path={}",
+        paths.partition_label,
+        paths.config_path
+    );
 
     let mut peripherals = Peripherals::take()?;
-    let mut storage = Box::new(SDCardStorage::new());
-    storage.install_driver(sd_peripherals!(peripherals))?;
-    storage.mount(&mount_path, 5)?;
-
-    log::info!("SD Card: {:#?}", CardInfo::new(&storage.card().unwrap()));
+    let mut storage = Box::new(SDCardStorage::new(sd_peripherals!(peripherals)));
+    // The config is on the card, so it can only be read with the default settings at first
+    let default_sd_settings = SDCardSettings::default();
+    // Without a working card, the flash partition is used as the drive instead
+    let flash_storage = match storage.mount_with_fallback(&default_sd_settings, mount_path, 5) {
+        Ok(()) => None,
+        Err(error) => {
+            log::error!(
+                "Failed to mount SD card with error: {error:#}, using the {} flash partition",
+                paths.partition_label
+            );
+            let mut flash_storage = SPIFlashStorage::new();
+            flash_storage.initialize_partition(&paths.partition_label)?;
+            flash_storage.mount(mount_path, 5)?;
+            Some(Box::new(flash_storage))
+        }
+    };
 
     let mut button = PinDriver::input(peripherals.pins.gpio14)?;
     button.set_pull(Pull::Up)?;
 
-    let config = load_config(Path::new(mount_path).join(config_path).to_str().unwrap());
+    let config_file = Path::new(mount_path).join(&paths.config_path);
+    let secret_key = nvs
+        .clone()
+        .map(SecretKey::load)
+        .transpose()
+        .unwrap_or_else(|error| {
+            log::warn!("Failed to load secret key with error: {error:#}");
+            None
+        })
+        .flatten()
+        .map(Arc::new);
+    let mut config_store = nvs.clone().and_then(|nvs| {
+        ConfigStore::new(nvs)
+            .map_err(|error| log::warn!("Failed to open config store with error: {error:#}"))
+            .ok()
+    });
+    let loaded_config = load_config(&config_file, secret_key.as_deref(), config_store.as_ref());
+    // Only a config from the file needs to be stored, and only once it proves to work
+    let mut config_to_store = loaded_config
+        .as_ref()
+        .filter(|loaded| loaded.source == ConfigSource::File)
+        .map(|loaded| loaded.content.clone());
+    let config_source = loaded_config
+        .as_ref()
+        .map(|loaded| Arc::new(RwLock::new(loaded.source)));
+    let mut config_content = loaded_config.as_ref().map(|loaded| loaded.content.clone());
+    let mut config = loaded_config.map(|loaded| loaded.config);
+    if let Some(config) = &config {
+        if let Err(error) = apply_log_config(config.log.as_ref()) {
+            log::error!("Failed to apply log config with error: {error:#}");
+        }
+    }
+    if let (Some(config), None) = (&config, &flash_storage) {
+        let configured_sd_settings = config
+            .sd
+            .as_ref()
+            .map(SDCardSettings::from)
+            .unwrap_or_default();
+        if configured_sd_settings != default_sd_settings {
+            log::info!("Remounting SD card with {configured_sd_settings:?} from the config");
+            storage.unmount();
+            if let Err(error) = storage.mount_with_fallback(&configured_sd_settings, mount_path, 5)
+            {
+                log::error!("Failed to mount SD card with the config, error: {error:#}");
+                storage.mount_with_fallback(&default_sd_settings, mount_path, 5)?;
+            }
+        }
+    }
+    if let Some(card) = storage.card() {
+        log::info!("SD Card: {:#?}", CardInfo::new(card));
+    }
+    let storage: Box<dyn Storage> = match flash_storage {
+        Some(flash_storage) => flash_storage,
+        None => storage,
+    };
 
-    let mut msc_config = MSCDeviceConfig::default();
+    let msc_config = config
+        .as_ref()
+        .and_then(|config| config.usb.as_ref())
+        .map(MSCDeviceConfig::from)
+        .unwrap_or_default();
     let mut msc_device = MSCDevice::new(&msc_config, storage);
     msc_device.install()?;
 
-    let mut _wifi: Option<Rc<WifiSession>> = None;
+    let outbox = nvs.clone().and_then(|nvs| {
+        Outbox::open(nvs, OUTBOX_MAX_EVENTS)
+            .map(|outbox| Rc::new(RefCell::new(outbox)))
+            .map_err(|error| {
+                log::error!("Failed to open outbox with error: {error:#}, events won't be reported")
+            })
+            .ok()
+    });
+    let mut monitor = match VolumeMonitor::new(mount_path, LOW_SPACE_RATIO) {
+        Ok(monitor) => Some(monitor),
+        Err(error) => {
+            log::error!("Failed to start volume monitor with error: {error:#}");
+            None
+        }
+    };
+    let mut config_watcher = ConfigWatcher::new(&config_file);
+
     let mut _sntp: Option<EspSntp> = None;
-    if let Some(config) = &config {
-        let mut wifi = WifiSession::new(
-            &WifiConfig {
-                ssid: config.wifi.ssid.clone(),
-                password: config.wifi.password.clone(),
-                auth_method: config.wifi.auth_method.as_ref().map(AuthMethod::from),
-            },
-            peripherals.modem,
-        )?;
-        wifi.connect().await?;
-        log::info!("Connected wifi: {:#?}", wifi.get_ip_info());
-        _wifi = Some(Rc::new(wifi));
+    let mut _lan_server: Option<LanServer> = None;
+    let mut _provisioning_portal: Option<ProvisioningPortal> = None;
+    let wifi_status = SharedWifiStatus::default();
+    let wifi_state = Rc::new(WifiStateSignal::new());
+    let api_connected_at = Rc::new(ApiConnectedAt::new(None));
+    let mut reloadable: Option<Reloadable> = None;
+    let mut config_editor: Option<Arc<ConfigEditor>> = None;
+    if let (Some(config), Some(config_source), Some(content)) =
+        (&config, &config_source, &config_content)
+    {
+        let wifi = match new_wifi_session(config, mount_path, peripherals.modem, nvs) {
+            Ok(wifi) => Some(wifi),
+            Err(error) => {
+                log::error!("Failed to initialize wifi with error: {error:#}");
+                None
+            }
+        };
+        let wifi_scanner = wifi.as_ref().map(|wifi| new_wifi_scanner(wifi.clone()));
+        let schedule = config
+            .schedule
+            .as_ref()
+            .map(Schedule::new)
+            .transpose()
+            .unwrap_or_else(|error| {
+                log::error!("Invalid schedule, staying connected all the time: {error:#}");
+                None
+            });
+        let wifi_reload = Rc::new(WifiReloadSignal::new());
+        let wifi_supervisor = wifi.clone().map(|wifi| {
+            WifiSupervisor::new(
+                wifi,
+                wifi_status.clone(),
+                wifi_state.clone(),
+                wifi_reload.clone(),
+                schedule,
+            )
+        });
+        match wifi_supervisor {
+            Some(Ok(mut wifi_supervisor)) => {
+                spawner.spawn_local(async move {
+                    if let Err(error) = wifi_supervisor.supervise().await {
+                        log::error!("Wifi supervisor stopped with error: {error:#}");
+                    }
+                })?;
+            }
+            Some(Err(error)) => {
+                log::error!("Failed to start wifi supervisor with error: {error:#}");
+            }
+            None => {}
+        }
 
         // Keep it around or else the SNTP service will stop
-        _sntp = Some(EspSntp::new_default()?);
-        log::info!("SNTP initialized");
-
-        let mut client = WebSocketSession::new(&config.api.endpoint, Duration::from_secs(30));
-
-        let captured_mount_path = mount_path.clone();
-        let mount_path_c_str = CString::new(mount_path.as_bytes())?;
-        let wifi_ref = _wifi.unwrap().clone();
-        let device_info_producer: DeviceInfoProducer = Box::new(move || {
-            let mut total_volume_size: u64 = 0;
-            let mut free_volume_size: u64 = 0;
-            esp!(unsafe {
-                esp_vfs_fat_info(
-                    mount_path_c_str.as_ptr(),
-                    &mut total_volume_size,
-                    &mut free_volume_size,
-                )
-            })?;
-            Ok(DeviceInfo {
-                version: VERSION.to_string(),
-                // TODO: maybe pass in Rc of wifi instead?
-                wifi_ip: wifi_ref.get_ip_info().unwrap().ip.to_string(),
-                local_time: OffsetDateTime::now_utc(),
-                mount_path: captured_mount_path.to_string(),
-                total_volume_size,
-                free_volume_size,
-            })
+        match EspSntp::new_default() {
+            Ok(sntp) => {
+                _sntp = Some(sntp);
+                log::info!("SNTP initialized");
+            }
+            Err(error) => log::error!("Failed to initialize SNTP with error: {error:#}"),
+        }
+
+        let api_config = Rc::new(RefCell::new(config.api.clone()));
+        let api_reload = Rc::new(ApiReloadSignal::new());
+        reloadable = Some(Reloadable {
+            wifi,
+            wifi_reload,
+            api_config: api_config.clone(),
+            api_reload: api_reload.clone(),
+        });
+        let captured_secret_key = secret_key.clone();
+        let editor = Arc::new(ConfigEditor::new(
+            &config_file,
+            content.clone(),
+            Box::new(move |value: &str| decrypt_value(captured_secret_key.as_deref(), value)),
+        ));
+        config_editor = Some(editor.clone());
+        let device_id = device_id().unwrap_or_else(|error| {
+            log::error!("Failed to read device id with error: {error:#}");
+            UNKNOWN_DEVICE_ID.to_string()
         });
+        let captured_paths = paths.clone();
+        let mdns = match Mdns::start(
+            &device_id,
+            VERSION,
+            config.http.as_ref().map(|http| http.port),
+        ) {
+            Ok(mdns) => Some(Arc::new(std::sync::Mutex::new(mdns))),
+            Err(error) => {
+                log::error!("Failed to start mDNS with error: {error:#}");
+                None
+            }
+        };
+        if let Some(http_config) = &config.http {
+            let result =
+                new_device_info_producer(&paths, wifi_status.clone(), config_source.clone())
+                    .and_then(|device_info_producer| {
+                        let processor = Processor {
+                            device_info_producer,
+                            root_dir: mount_path.to_string(),
+                            active_endpoint: None,
+                            wifi_scanner: wifi_scanner.clone(),
+                            config_editor: Some(editor.clone()),
+                        };
+                        LanServer::start(http_config, processor)
+                    });
+            match result {
+                Ok(server) => _lan_server = Some(server),
+                Err(error) => log::error!("Failed to start LAN HTTP server with error: {error:#}"),
+            }
+        }
 
-        spawner.spawn_local(process_events(
-            client,
-            device_info_producer,
-            mount_path.to_string(),
-        ))?;
+        let captured_wifi_status = wifi_status.clone();
+        let captured_config_source = config_source.clone();
+        let session_factory: SessionFactory = Box::new(move || {
+            let api_config = api_config.borrow().clone();
+            let mdns = mdns.clone();
+            let device_id = device_id.clone();
+            let captured_paths = captured_paths.clone();
+            let captured_wifi_status = captured_wifi_status.clone();
+            let captured_config_source = captured_config_source.clone();
+            async move {
+                let mut endpoints = vec![];
+                if let (true, Some(mdns)) = (api_config.discover, mdns) {
+                    match discover_in_background(mdns, DISCOVERY_TIMEOUT).await {
+                        Ok(discovered) => endpoints.extend(discovered),
+                        Err(error) => {
+                            log::warn!("Failed to discover servers with error: {error:#}")
+                        }
+                    }
+                }
+                endpoints.extend(api_config.endpoints.iter().cloned());
+                let client: Box<dyn Transport> = match api_config.transport {
+                    ApiTransport::WebSocket => {
+                        Box::new(WebSocketSession::new(&endpoints, Duration::from_secs(30)))
+                    }
+                    ApiTransport::Http => {
+                        Box::new(HttpSession::new(&endpoints, Duration::from_secs(30)))
+                    }
+                    ApiTransport::Mqtt => Box::new(MqttSession::new(
+                        &endpoints,
+                        Duration::from_secs(30),
+                        &device_id,
+                    )),
+                };
+                let device_info_producer = new_device_info_producer(
+                    &captured_paths,
+                    captured_wifi_status,
+                    captured_config_source,
+                )?;
+                Ok((client, device_info_producer))
+            }
+            .boxed_local()
+        });
+
+        let root_dir = mount_path.to_string();
+        let captured_outbox = outbox.clone();
+        let captured_wifi_status = wifi_status.clone();
+        let captured_wifi_state = wifi_state.clone();
+        let captured_api_connected_at = api_connected_at.clone();
+        spawner.spawn_local(async move {
+            if let Err(error) = supervise_events(
+                session_factory,
+                root_dir,
+                wifi_scanner,
+                Some(editor),
+                captured_outbox,
+                captured_wifi_status,
+                captured_wifi_state,
+                api_reload,
+                captured_api_connected_at,
+            )
+            .await
+            {
+                log::error!("Event loop supervisor stopped with error: {error:#}");
+            }
+        })?;
+    } else {
+        log::info!("No valid config, starting provisioning portal");
+        let device_id = device_id().unwrap_or_else(|_| UNKNOWN_DEVICE_ID.to_string());
+        match ProvisioningPortal::start(
+            peripherals.modem,
+            nvs,
+            &config_file,
+            &device_id,
+            secret_key.clone(),
+        ) {
+            Ok(portal) => _provisioning_portal = Some(portal),
+            Err(error) => log::error!("Failed to start provisioning portal with error: {error:#}"),
+        }
     }
 
     let timer_service = EspTimerService::new().unwrap();
     let mut timer = timer_service.timer_async()?;
+    let mut pending_rollback: Option<PendingRollback> = None;
     loop {
-        timer.after(Duration::from_secs(1)).await?;
+        let config_updated = async {
+            match &config_editor {
+                Some(config_editor) => config_editor.wait_updated().await,
+                None => pending().await,
+            }
+        };
+        // The content before a remote update, in case it has to be rolled back
+        let mut previous_content =
+            match select(pin!(timer.after(MONITOR_INTERVAL)), pin!(config_updated)).await {
+                Either::Left((result, _)) => {
+                    result?;
+                    None
+                }
+                Either::Right((previous_content, _)) => Some(previous_content),
+            };
+        if let Some(monitor) = &mut monitor {
+            let mut outbox = outbox.as_ref().map(|outbox| outbox.borrow_mut());
+            if let Err(error) = monitor.poll(outbox.as_deref_mut()) {
+                log::warn!("Failed to poll volume monitor with error: {error}");
+            }
+        }
+        let mut rolled_back = false;
+        if let Some(rollback) = &pending_rollback {
+            let wifi_status = wifi_status.read().unwrap().clone();
+            let wifi_connected = wifi_status.state == WifiState::Connected
+                && wifi_status.connected_at > Some(rollback.applied_at);
+            let api_connected = api_connected_at.get() > Some(rollback.applied_at);
+            if (wifi_connected || !rollback.wifi_changed)
+                && (api_connected || !rollback.api_changed)
+            {
+                log::info!("Connected with the updated config");
+                pending_rollback = None;
+            } else if rollback.applied_at.elapsed() >= CONFIG_ROLLBACK_TIMEOUT {
+                log::warn!(
+                    "Failed to connect within {} secs after the config update, wifi={}, api={}, \
+                     rolling back",
+                    CONFIG_ROLLBACK_TIMEOUT.as_secs(),
+                    wifi_connected,
+                    api_connected
+                );
+                let content = pending_rollback.take().unwrap().content;
+                if let Some(config_editor) = &config_editor {
+                    match config_editor.restore(content) {
+                        Ok(()) => rolled_back = true,
+                        Err(error) => {
+                            log::error!("Failed to roll back config with error: {error:#}")
+                        }
+                    }
+                }
+            }
+        }
+        let remounted = monitor.as_mut().is_some_and(VolumeMonitor::take_remounted);
+        if config_watcher.has_changed(remounted) || previous_content.is_some() || rolled_back {
+            let result = read_config_file(&config_file, secret_key.as_deref())
+                .and_then(|content| Ok((parse_config(&content, secret_key.as_deref())?, content)));
+            match result {
+                Ok((_, content)) if config_content.as_ref() == Some(&content) => {}
+                Ok((new_config, content)) => {
+                    if let (Some(current_config), Some(reloadable)) = (&config, &reloadable) {
+                        log::info!("Config file changed, applying it");
+                        let wifi_changed = new_config.wifi != current_config.wifi;
+                        let api_changed = new_config.api != current_config.api;
+                        apply_config_changes(current_config, &new_config, mount_path, reloadable)
+                            .await;
+                        match previous_content.take() {
+                            // Keep the oldest content if the last update is still pending
+                            Some(previous_content) if wifi_changed || api_changed => {
+                                pending_rollback = Some(match pending_rollback.take() {
+                                    Some(rollback) => PendingRollback {
+                                        applied_at: Instant::now(),
+                                        wifi_changed: rollback.wifi_changed || wifi_changed,
+                                        api_changed: rollback.api_changed || api_changed,
+                                        ..rollback
+                                    },
+                                    None => PendingRollback {
+                                        content: previous_content,
+                                        applied_at: Instant::now(),
+                                        wifi_changed,
+                                        api_changed,
+                                    },
+                                });
+                            }
+                            Some(_) => {}
+                            // Changed by the USB host or rolled back, nothing to go back to
+                            None => pending_rollback = None,
+                        }
+                        if let Some(config_editor) = &config_editor {
+                            config_editor.set_content(content.clone());
+                        }
+                        config = Some(new_config);
+                        config_content = Some(content.clone());
+                        // Reloaded or rolled back, it's from the file either way
+                        if let Some(config_source) = &config_source {
+                            *config_source.write().unwrap() = ConfigSource::File;
+                        }
+                        config_to_store = Some(content);
+                    } else {
+                        // Nothing is running without a config, so just start over with it
+                        log::info!("Valid config file found, restarting with it");
+                        restart();
+                    }
+                }
+                Err(error) => {
+                    log::warn!("Ignored the changed config file with error: {error:#}");
+                }
+            }
+        }
+        // A config pending rollback hasn't proven to work yet
+        if config_to_store.is_some()
+            && pending_rollback.is_none()
+            && wifi_status.read().unwrap().state == WifiState::Connected
+        {
+            let content = config_to_store.take().unwrap();
+            if let Some(config_store) = &mut config_store {
+                match config_store.save(&content) {
+                    Ok(true) => log::info!("Stored known-good config into NVS"),
+                    Ok(false) => log::info!("Known-good config in NVS is up to date"),
+                    Err(error) => log::warn!("Failed to store config with error: {error:#}"),
+                }
+            }
+        }
     }
 
     Ok(())
@@ -167,8 +979,7 @@ fn main() -> Result<(), anyhow::Error> {
             let mut local_executor = LocalPool::new();
             let spawner = local_executor.spawner();
             local_executor.run_until(async move { run_async(spawner).await })
-        })
-        .unwrap()
+        })?
         .join()
-        .unwrap()
+        .map_err(|_| anyhow!("Executor thread panicked"))?
 }
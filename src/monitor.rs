@@ -0,0 +1,134 @@
+use crate::api::outbox::{Event, Outbox};
+use crate::usb::msc_device::{in_use_by_usb_host, MOUNT_CHANGED_EVENTS};
+use esp_idf_svc::sys::{esp, esp_vfs_fat_info};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::read_dir;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const SENTRY_CLIPS_PATH: &str = "TeslaCam/SentryClips";
+
+fn push(outbox: &mut Option<&mut Outbox>, event: Event) -> anyhow::Result<()> {
+    match outbox {
+        Some(outbox) => {
+            outbox.push(event)?;
+        }
+        None => log::info!("No outbox to queue event {event:?}"),
+    }
+    Ok(())
+}
+
+/// Watches the mounted volume and the USB mass storage for things the server should know about,
+/// and queues them into the outbox as events.
+pub struct VolumeMonitor {
+    mount_path: CString,
+    root_dir: PathBuf,
+    sentry_clips_dir: PathBuf,
+    /// `None` until the clips can be listed, so that existing clips are never reported as new
+    known_clips: Option<HashSet<String>>,
+    low_space_ratio: f64,
+    is_low_space: bool,
+    remounted: bool,
+}
+
+impl VolumeMonitor {
+    pub fn new(mount_path: &str, low_space_ratio: f64) -> anyhow::Result<Self> {
+        let mut monitor = Self {
+            mount_path: CString::new(mount_path.as_bytes())?,
+            root_dir: PathBuf::from(mount_path),
+            sentry_clips_dir: Path::new(mount_path).join(SENTRY_CLIPS_PATH),
+            known_clips: None,
+            low_space_ratio,
+            is_low_space: false,
+            remounted: false,
+        };
+        // Clips recorded before we boot are not new, only remember them
+        monitor.known_clips = monitor.list_clips();
+        Ok(monitor)
+    }
+
+    /// Without an outbox, the events are only logged
+    pub fn poll(&mut self, mut outbox: Option<&mut Outbox>) -> anyhow::Result<()> {
+        while let Ok(is_mounted) = MOUNT_CHANGED_EVENTS.try_receive() {
+            self.remounted |= is_mounted;
+            push(&mut outbox, Event::UsbMountChanged { is_mounted })?;
+        }
+
+        // The volume isn't mounted for us meanwhile, there's nothing to look at until it's back
+        if in_use_by_usb_host() {
+            return Ok(());
+        }
+
+        let Some(clips) = self.list_clips() else {
+            return Ok(());
+        };
+        if let Some(known_clips) = &self.known_clips {
+            for clip in clips.difference(known_clips) {
+                push(
+                    &mut outbox,
+                    Event::NewSentryClip {
+                        path: self
+                            .sentry_clips_dir
+                            .join(clip)
+                            .to_string_lossy()
+                            .to_string(),
+                    },
+                )?;
+            }
+        }
+        self.known_clips = Some(clips);
+
+        let mut total_volume_size: u64 = 0;
+        let mut free_volume_size: u64 = 0;
+        esp!(unsafe {
+            esp_vfs_fat_info(
+                self.mount_path.as_ptr(),
+                &mut total_volume_size,
+                &mut free_volume_size,
+            )
+        })?;
+        let is_low_space =
+            (free_volume_size as f64) < (total_volume_size as f64) * self.low_space_ratio;
+        // Only report when we cross the threshold, not on every poll
+        if is_low_space && !self.is_low_space {
+            push(
+                &mut outbox,
+                Event::LowSpace {
+                    total_volume_size,
+                    free_volume_size,
+                },
+            )?;
+        }
+        self.is_low_space = is_low_space;
+        Ok(())
+    }
+
+    /// Whether the volume was mounted back to us since the last call, which means the USB host
+    /// may have changed any file on it
+    pub fn take_remounted(&mut self) -> bool {
+        std::mem::take(&mut self.remounted)
+    }
+
+    /// `None` if the clips can't be read, like when the volume isn't mounted, as opposed to
+    /// an empty set when there's no clip folder yet
+    fn list_clips(&self) -> Option<HashSet<String>> {
+        let entries = match read_dir(&self.sentry_clips_dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound && self.root_dir.is_dir() => {
+                return Some(HashSet::new());
+            }
+            Err(error) => {
+                log::debug!("Failed to list sentry clips with error: {error}");
+                return None;
+            }
+        };
+        let mut clips = HashSet::new();
+        for entry in entries.flatten() {
+            if let Ok(name) = entry.file_name().into_string() {
+                clips.insert(name);
+            }
+        }
+        Some(clips)
+    }
+}
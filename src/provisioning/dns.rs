@@ -0,0 +1,63 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::thread;
+
+const DNS_PORT: u16 = 53;
+const STACK_SIZE: usize = 4096;
+const MAX_PACKET_SIZE: usize = 512;
+const DNS_HEADER_SIZE: usize = 12;
+
+/// Build an answer pointing the first question of the query to the given address
+fn build_response(query: &[u8], addr: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < DNS_HEADER_SIZE {
+        return None;
+    }
+    // Find the end of the question: QNAME labels, then QTYPE and QCLASS
+    let mut index = DNS_HEADER_SIZE;
+    while *query.get(index)? != 0 {
+        index += *query.get(index)? as usize + 1;
+    }
+    let question_end = index + 1 + 4;
+    if question_end > query.len() {
+        return None;
+    }
+
+    let mut response = Vec::with_capacity(question_end + 16);
+    // Same id, flags set to standard response with no error and recursion available
+    response.extend_from_slice(&query[0..2]);
+    response.extend_from_slice(&[0x81, 0x80]);
+    // 1 question, 1 answer, 0 authority, 0 additional
+    response.extend_from_slice(&[0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    response.extend_from_slice(&query[DNS_HEADER_SIZE..question_end]);
+    // Answer: pointer to the name in the question, type A, class IN, TTL 60, 4 bytes of addr
+    response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+    response.extend_from_slice(&[0x00, 0x04]);
+    response.extend_from_slice(&addr.octets());
+    Some(response)
+}
+
+/// A DNS server answering every query with our own address, so that the phone or laptop
+/// connecting to the access point pops up the captive portal
+pub fn start_captive_dns(addr: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut buf = [0u8; MAX_PACKET_SIZE];
+            loop {
+                let (size, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(error) => {
+                        log::warn!("Failed to receive DNS query with error: {error}");
+                        continue;
+                    }
+                };
+                if let Some(response) = build_response(&buf[..size], addr) {
+                    if let Err(error) = socket.send_to(&response, peer) {
+                        log::warn!("Failed to send DNS response with error: {error}");
+                    }
+                }
+            }
+        })?;
+    log::info!("Captive DNS server started, resolving everything to {addr}");
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use crate::config::migrate::CURRENT_SCHEMA_VERSION;
+use crate::config::Config;
+use crate::encoding::form_decode;
+use crate::provisioning::dns::start_captive_dns;
+use crate::secrets::SecretKey;
+use anyhow::{anyhow, bail};
+use embedded_svc::http::Method;
+use embedded_svc::wifi::{
+    AccessPointConfiguration, AccessPointInfo, AuthMethod, ClientConfiguration, Configuration,
+};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::hal::reset::restart;
+use esp_idf_svc::http::server::{self, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::mbedtls_sha256;
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const AP_SSID_PREFIX: &str = "SecureDash";
+const AP_CHANNEL: u8 = 1;
+/// Hashed along with the device id into the access point passphrase, see `scripts/secret.py`
+const AP_PASSPHRASE_SALT: &str = "securedash-setup:";
+/// In hex digits, out of the SHA-256 hash
+const AP_PASSPHRASE_LEN: usize = 16;
+const STACK_SIZE: usize = 10240;
+const MAX_FORM_SIZE: usize = 2048;
+/// Give the browser a moment to receive the response before we restart
+const RESTART_DELAY: Duration = Duration::from_secs(3);
+
+/// Serialized as a plain string, or as `{ encrypted = "..." }`
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ProvisionedSecret {
+    Plain(String),
+    Encrypted { encrypted: String },
+}
+
+#[derive(Serialize)]
+struct ProvisionedNetwork {
+    ssid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<ProvisionedSecret>,
+}
+
+#[derive(Serialize)]
+struct ProvisionedWifi {
+    networks: Vec<ProvisionedNetwork>,
+}
+
+#[derive(Serialize)]
+struct ProvisionedEndpoint {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct ProvisionedApi {
+    endpoints: Vec<ProvisionedEndpoint>,
+}
+
+/// The minimal config produced by the portal, serialized into `securedash.toml`
+#[derive(Serialize)]
+struct ProvisionedConfig {
+    schema_version: i64,
+    wifi: ProvisionedWifi,
+    api: ProvisionedApi,
+}
+
+/// The WPA2 passphrase of the access point, the first hex digits of the SHA-256 hash of the salt
+/// and the device id. It keeps the form off an open network, but anyone who knows the device id
+/// can work it out.
+fn ap_passphrase(device_id: &str) -> anyhow::Result<String> {
+    let input = format!("{AP_PASSPHRASE_SALT}{device_id}");
+    let mut hash = [0u8; 32];
+    let result = unsafe { mbedtls_sha256(input.as_ptr(), input.len(), hash.as_mut_ptr(), 0) };
+    if result != 0 {
+        bail!("Failed to hash the access point passphrase with error code {result}");
+    }
+    let hex: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+    Ok(hex[..AP_PASSPHRASE_LEN].to_string())
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (form_decode(key), form_decode(value)))
+        .collect()
+}
+
+fn render_page(access_points: &[AccessPointInfo], message: Option<&str>) -> String {
+    let mut seen = HashSet::new();
+    let options: String = access_points
+        .iter()
+        .map(|ap| ap.ssid.as_str())
+        .filter(|ssid| !ssid.is_empty() && seen.insert(*ssid))
+        .map(|ssid| format!("<option value=\"{0}\">{0}</option>", html_escape(ssid)))
+        .collect();
+    let message = message
+        .map(|message| format!("<p><b>{}</b></p>", html_escape(message)))
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+<title>SecureDash setup</title></head><body>\
+<h1>SecureDash setup</h1>{message}\
+<form method=\"post\" action=\"/save\">\
+<p><label>Wi-Fi network<br><input name=\"ssid\" list=\"networks\" required></label>\
+<datalist id=\"networks\">{options}</datalist> <a href=\"/\">Rescan</a></p>\
+<p><label>Wi-Fi password<br><input name=\"password\" type=\"password\"></label></p>\
+<p><label>API endpoint<br><input name=\"endpoint\" type=\"url\" \
+placeholder=\"wss://example.com/ws\" required></label></p>\
+<p><button type=\"submit\">Save and restart</button></p>\
+</form></body></html>"
+    )
+}
+
+fn send_page(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    page: &str,
+) -> anyhow::Result<()> {
+    request
+        .into_response(
+            status,
+            None,
+            &[("Content-Type", "text/html; charset=utf-8")],
+        )?
+        .write_all(page.as_bytes())?;
+    Ok(())
+}
+
+fn read_body(request: &mut Request<&mut EspHttpConnection>) -> anyhow::Result<String> {
+    let mut body: Vec<u8> = vec![];
+    let mut buf = [0u8; 256];
+    loop {
+        let read_size = request.read(&mut buf)?;
+        if read_size == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..read_size]);
+        if body.len() > MAX_FORM_SIZE {
+            bail!("Form too large");
+        }
+    }
+    Ok(String::from_utf8(body)?)
+}
+
+/// Turn the submitted form into the content of `securedash.toml`, making sure it loads. The
+/// password is encrypted with the secret key, unless there's none.
+fn build_config(
+    form: &HashMap<String, String>,
+    secret_key: Option<&SecretKey>,
+) -> anyhow::Result<String> {
+    let field = |key: &str| {
+        form.get(key)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let ssid = field("ssid").ok_or_else(|| anyhow!("Wi-Fi network is required"))?;
+    let endpoint = field("endpoint").ok_or_else(|| anyhow!("API endpoint is required"))?;
+    // Passwords may legitimately have leading or trailing spaces, keep it as-is
+    let password = form
+        .get("password")
+        .filter(|value| !value.is_empty())
+        .map(|password| match secret_key {
+            Some(secret_key) => Ok(ProvisionedSecret::Encrypted {
+                encrypted: secret_key.encrypt(password)?,
+            }),
+            None => Ok(ProvisionedSecret::Plain(password.clone())),
+        })
+        .transpose()?;
+    let content = toml::to_string(&ProvisionedConfig {
+        schema_version: CURRENT_SCHEMA_VERSION,
+        wifi: ProvisionedWifi {
+            networks: vec![ProvisionedNetwork { ssid, password }],
+        },
+        api: ProvisionedApi {
+            endpoints: vec![ProvisionedEndpoint { url: endpoint }],
+        },
+    })?;
+    Config::parse(&content, &|value| match secret_key {
+        Some(secret_key) => secret_key.decrypt(value),
+        None => bail!("No secret key to decrypt the value"),
+    })?;
+    Ok(content)
+}
+
+/// An existing config is invalid rather than missing, so it's kept as `<name>.bak` instead of
+/// being overwritten, in case it only needs a small fix
+fn back_up_config(config_file: &Path) -> anyhow::Result<()> {
+    if !config_file.exists() {
+        return Ok(());
+    }
+    let file_name = config_file
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid config path {config_file:?}"))?
+        .to_string_lossy();
+    let backup_file = config_file.with_file_name(format!("{file_name}.bak"));
+    std::fs::copy(config_file, &backup_file)?;
+    log::info!("Kept the existing config as {backup_file:?}");
+    Ok(())
+}
+
+/// The fallback mode when there's no valid config on the volume. It brings up a WPA2 access point
+/// named `SecureDash-<id>` with a captive portal, which scans for networks, takes the Wi-Fi
+/// credentials and the API endpoint, writes `securedash.toml` with the password encrypted and
+/// restarts the device.
+pub struct ProvisioningPortal {
+    _wifi: Arc<Mutex<BlockingWifi<EspWifi<'static>>>>,
+    _server: EspHttpServer<'static>,
+}
+
+impl ProvisioningPortal {
+    pub fn start(
+        modem: Modem,
+        nvs: Option<EspDefaultNvsPartition>,
+        config_file: &Path,
+        device_id: &str,
+        secret_key: Option<Arc<SecretKey>>,
+    ) -> anyhow::Result<Self> {
+        // Without a key, the password would be written in plaintext
+        let secret_key = secret_key.or_else(|| {
+            nvs.clone()
+                .map(SecretKey::generate)
+                .transpose()
+                .unwrap_or_else(|error| {
+                    log::warn!("Failed to generate secret key with error: {error:#}");
+                    None
+                })
+                .map(Arc::new)
+        });
+        let sys_loop = EspSystemEventLoop::take()?;
+        let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sys_loop.clone(), nvs)?, sys_loop)?;
+        let suffix = &device_id[device_id.len().saturating_sub(4)..];
+        let ap_ssid = format!("{AP_SSID_PREFIX}-{suffix}");
+        let ap_passphrase = ap_passphrase(device_id)?;
+        // Keep the client interface around as well so that we can scan for networks
+        wifi.set_configuration(&Configuration::Mixed(
+            ClientConfiguration::default(),
+            AccessPointConfiguration {
+                ssid: ap_ssid.as_str().try_into().unwrap(),
+                password: ap_passphrase.as_str().try_into().unwrap(),
+                auth_method: AuthMethod::WPA2Personal,
+                channel: AP_CHANNEL,
+                ..Default::default()
+            },
+        ))?;
+        // No waiting for the netif here, it only counts as up once the client is connected
+        wifi.start()?;
+        let ap_ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+        log::info!("Provisioning access point {ap_ssid} started at {ap_ip}");
+
+        start_captive_dns(ap_ip)?;
+
+        let wifi = Arc::new(Mutex::new(wifi));
+        let mut server = EspHttpServer::new(&server::Configuration {
+            stack_size: STACK_SIZE,
+            uri_match_wildcard: true,
+            ..Default::default()
+        })?;
+
+        let captured_wifi = wifi.clone();
+        server.fn_handler("/", Method::Get, move |request| -> anyhow::Result<()> {
+            let access_points = captured_wifi
+                .lock()
+                .unwrap()
+                .scan()
+                .unwrap_or_else(|error| {
+                    log::warn!("Failed to scan networks with error: {error}");
+                    vec![]
+                });
+            send_page(request, 200, &render_page(&access_points, None))
+        })?;
+
+        let config_file: PathBuf = config_file.to_path_buf();
+        server.fn_handler(
+            "/save",
+            Method::Post,
+            move |mut request| -> anyhow::Result<()> {
+                let form = parse_form(&read_body(&mut request)?);
+                let content = match build_config(&form, secret_key.as_deref()) {
+                    Ok(content) => content,
+                    Err(error) => {
+                        return send_page(
+                            request,
+                            400,
+                            &render_page(&[], Some(&format!("{error}"))),
+                        );
+                    }
+                };
+                let result = back_up_config(&config_file)
+                    .and_then(|()| Ok(std::fs::write(&config_file, content)?));
+                if let Err(error) = result {
+                    log::error!("Failed to write config {config_file:?} with error: {error:#}");
+                    return send_page(
+                        request,
+                        500,
+                        &render_page(&[], Some(&format!("Failed to save config: {error}"))),
+                    );
+                }
+                log::info!("Config written to {config_file:?}, restarting");
+                send_page(
+                    request,
+                    200,
+                    "<!DOCTYPE html><html><body><h1>Saved</h1>\
+<p>The device is restarting and connecting to your network.</p></body></html>",
+                )?;
+                thread::spawn(|| {
+                    thread::sleep(RESTART_DELAY);
+                    restart();
+                });
+                Ok(())
+            },
+        )?;
+
+        // Everything else, like the captive portal probes from phones and laptops, is redirected
+        // to the setup page
+        let location = format!("http://{ap_ip}/");
+        server.fn_handler("/*", Method::Get, move |request| -> anyhow::Result<()> {
+            request.into_response(302, Some("Found"), &[("Location", location.as_str())])?;
+            Ok(())
+        })?;
+
+        log::info!("Provisioning portal started");
+        Ok(Self {
+            _wifi: wifi,
+            _server: server,
+        })
+    }
+}
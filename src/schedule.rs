@@ -0,0 +1,126 @@
+use crate::config;
+use crate::wifi::session::PowerSave;
+use anyhow::{anyhow, bail};
+use time::{OffsetDateTime, Time, UtcOffset};
+
+/// Before SNTP syncs the clock, it starts from 1970. Anything before this year means we don't
+/// know the time yet.
+const MIN_SYNCED_YEAR: i32 = 2024;
+
+/// What to do with Wi-Fi outside the scheduled windows
+#[derive(Debug, Clone, Copy)]
+pub enum IdleMode {
+    WifiOff,
+    PowerSave(PowerSave),
+}
+
+/// The daily windows during which Wi-Fi and the API session may be active
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    windows: Vec<(Time, Time)>,
+    utc_offset: UtcOffset,
+    pub idle_mode: IdleMode,
+}
+
+/// Parse `HH:MM` into its hour and minute
+fn parse_hour_minute(value: &str) -> anyhow::Result<(u8, u8)> {
+    let (hour, minute) = value
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid time {value:?}, expected HH:MM"))?;
+    Ok((hour.parse()?, minute.parse()?))
+}
+
+fn parse_time(value: &str) -> anyhow::Result<Time> {
+    let (hour, minute) = parse_hour_minute(value)?;
+    Ok(Time::from_hms(hour, minute, 0)?)
+}
+
+/// Parse `+HH:MM` or `-HH:MM` into an UTC offset
+fn parse_utc_offset(value: &str) -> anyhow::Result<UtcOffset> {
+    let (sign, rest) = if let Some(rest) = value.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = value.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        bail!("Invalid UTC offset {value:?}, expected +HH:MM or -HH:MM");
+    };
+    let (hour, minute) = parse_hour_minute(rest)?;
+    // Checked before the cast, as larger values would wrap around into a valid offset
+    if hour > 23 || minute > 59 {
+        bail!("Invalid UTC offset {value:?}, the hour must be up to 23 and the minute up to 59");
+    }
+    Ok(UtcOffset::from_hms(
+        sign * hour as i8,
+        sign * minute as i8,
+        0,
+    )?)
+}
+
+impl Schedule {
+    pub fn new(config: &config::Schedule) -> anyhow::Result<Self> {
+        if config.windows.is_empty() {
+            bail!("At least one schedule window is required");
+        }
+        let windows = config
+            .windows
+            .iter()
+            .map(|window| Ok((parse_time(&window.start)?, parse_time(&window.end)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let utc_offset = match &config.utc_offset {
+            Some(utc_offset) => parse_utc_offset(utc_offset)?,
+            None => UtcOffset::UTC,
+        };
+        let idle_mode = match config.idle {
+            config::IdleMode::WifiOff => IdleMode::WifiOff,
+            config::IdleMode::MaxModem => IdleMode::PowerSave(PowerSave::MaxModem),
+        };
+        Ok(Self {
+            windows,
+            utc_offset,
+            idle_mode,
+        })
+    }
+
+    /// Whether the given moment falls into any of the windows. Windows with the end before the
+    /// start span across midnight. It's always active until the clock is synced, as SNTP needs
+    /// the network.
+    pub fn is_active(&self, now: OffsetDateTime) -> bool {
+        if now.year() < MIN_SYNCED_YEAR {
+            return true;
+        }
+        let time = now.to_offset(self.utc_offset).time();
+        self.windows.iter().any(|(start, end)| {
+            if start <= end {
+                *start <= time && time < *end
+            } else {
+                time >= *start || time < *end
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(idle: &str) -> anyhow::Result<Schedule> {
+        let config: config::Schedule = toml::from_str(&format!(
+            "idle = \"{idle}\"\n[[windows]]\nstart = \"01:00\"\nend = \"05:00\"\n"
+        ))?;
+        Schedule::new(&config)
+    }
+
+    #[test]
+    fn idle_modes_save_more_power_than_active() {
+        assert!(matches!(
+            schedule("wifi_off").unwrap().idle_mode,
+            IdleMode::WifiOff
+        ));
+        assert!(matches!(
+            schedule("max_modem").unwrap().idle_mode,
+            IdleMode::PowerSave(PowerSave::MaxModem)
+        ));
+        // The power save mode while active, so idling with it would change nothing
+        assert!(schedule("min_modem").is_err());
+    }
+}
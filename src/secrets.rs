@@ -0,0 +1,129 @@
+use crate::encoding::{base64_decode, base64_encode};
+use anyhow::bail;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::{
+    esp_fill_random, mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES, mbedtls_gcm_auth_decrypt,
+    mbedtls_gcm_context, mbedtls_gcm_crypt_and_tag, mbedtls_gcm_free, mbedtls_gcm_init,
+    mbedtls_gcm_setkey, MBEDTLS_GCM_ENCRYPT,
+};
+use std::ffi::c_void;
+use std::{mem, ptr};
+
+const NVS_NAMESPACE: &str = "securedash";
+const NVS_KEY: &str = "secret_key";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// The per-device AES-256 key for decrypting the `{ encrypted = "..." }` values in the config.
+/// It's provisioned into NVS by `scripts/secret.py`, or generated by the provisioning portal, so
+/// it never touches the drive.
+pub struct SecretKey([u8; KEY_SIZE]);
+
+fn fill_random(buf: &mut [u8]) {
+    unsafe { esp_fill_random(buf.as_mut_ptr() as *mut c_void, buf.len()) };
+}
+
+impl SecretKey {
+    pub fn load(nvs: EspDefaultNvsPartition) -> anyhow::Result<Option<Self>> {
+        let nvs = EspNvs::new(nvs, NVS_NAMESPACE, true)?;
+        let mut buf = [0u8; KEY_SIZE];
+        match nvs.get_blob(NVS_KEY, &mut buf)? {
+            Some(key) if key.len() == KEY_SIZE => Ok(Some(Self(buf))),
+            Some(key) => bail!("Invalid secret key size {}", key.len()),
+            None => Ok(None),
+        }
+    }
+
+    /// Generate a new key and store it into NVS, for a device without one which gets its config
+    /// from the provisioning portal
+    pub fn generate(nvs: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let mut nvs = EspNvs::new(nvs, NVS_NAMESPACE, true)?;
+        let mut key = [0u8; KEY_SIZE];
+        fill_random(&mut key);
+        nvs.set_blob(NVS_KEY, &key)?;
+        log::info!("Generated a new secret key");
+        Ok(Self(key))
+    }
+
+    /// Encrypt a value into the format taken by `decrypt`, with a random nonce
+    pub fn encrypt(&self, value: &str) -> anyhow::Result<String> {
+        let mut data = vec![0u8; NONCE_SIZE + value.len() + TAG_SIZE];
+        let (nonce, rest) = data.split_at_mut(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at_mut(value.len());
+        fill_random(nonce);
+        let result = unsafe {
+            let mut ctx: mbedtls_gcm_context = mem::zeroed();
+            mbedtls_gcm_init(&mut ctx);
+            let mut result = mbedtls_gcm_setkey(
+                &mut ctx,
+                mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+                self.0.as_ptr(),
+                (KEY_SIZE * 8) as u32,
+            );
+            if result == 0 {
+                result = mbedtls_gcm_crypt_and_tag(
+                    &mut ctx,
+                    MBEDTLS_GCM_ENCRYPT as i32,
+                    value.len(),
+                    nonce.as_ptr(),
+                    nonce.len(),
+                    ptr::null(),
+                    0,
+                    value.as_ptr(),
+                    ciphertext.as_mut_ptr(),
+                    tag.len(),
+                    tag.as_mut_ptr(),
+                );
+            }
+            mbedtls_gcm_free(&mut ctx);
+            result
+        };
+        if result != 0 {
+            bail!("Failed to encrypt value with error code {result}");
+        }
+        base64_encode(&data)
+    }
+
+    /// Decrypt a value in the format of base64 encoded `nonce (12 bytes) || ciphertext || tag
+    /// (16 bytes)` with AES-256-GCM
+    pub fn decrypt(&self, value: &str) -> anyhow::Result<String> {
+        let data = base64_decode(value.trim())?;
+        if data.len() < NONCE_SIZE + TAG_SIZE {
+            bail!("Encrypted value is too short");
+        }
+        let (nonce, rest) = data.split_at(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let result = unsafe {
+            let mut ctx: mbedtls_gcm_context = mem::zeroed();
+            mbedtls_gcm_init(&mut ctx);
+            let mut result = mbedtls_gcm_setkey(
+                &mut ctx,
+                mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+                self.0.as_ptr(),
+                (KEY_SIZE * 8) as u32,
+            );
+            if result == 0 {
+                result = mbedtls_gcm_auth_decrypt(
+                    &mut ctx,
+                    ciphertext.len(),
+                    nonce.as_ptr(),
+                    nonce.len(),
+                    ptr::null(),
+                    0,
+                    tag.as_ptr(),
+                    tag.len(),
+                    ciphertext.as_ptr(),
+                    plaintext.as_mut_ptr(),
+                );
+            }
+            mbedtls_gcm_free(&mut ctx);
+            result
+        };
+        if result != 0 {
+            bail!("Failed to decrypt value with error code {result}, is it for this device?");
+        }
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
@@ -1,14 +1,26 @@
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use esp_idf_svc::fs::fatfs::Fatfs;
 use esp_idf_svc::hal::gpio;
 use esp_idf_svc::hal::gpio::{Gpio33, Gpio34, Gpio35, Gpio36, Gpio37, Gpio38};
+use esp_idf_svc::hal::peripheral::Peripheral;
 use esp_idf_svc::hal::sd::mmc::{SdMmcHostConfiguration, SdMmcHostDriver, SDMMC1};
 use esp_idf_svc::hal::sd::{SdCardConfiguration, SdCardDriver};
 use esp_idf_svc::io::vfs::MountedFatfs;
-use esp_idf_svc::sys::{esp, ff_diskio_get_drive, sdmmc_card_t, SDMMC_FREQ_52M};
+use esp_idf_svc::sys::{
+    esp, ff_diskio_get_drive, sdmmc_card_t, SDMMC_FREQ_26M, SDMMC_FREQ_52M, SDMMC_FREQ_DEFAULT,
+    SDMMC_FREQ_HIGHSPEED,
+};
 use std::borrow::Borrow;
 use std::mem::replace;
 
+/// The frequencies in kHz to step down through when the card doesn't work
+const FALLBACK_FREQUENCIES_KHZ: &[u32] = &[
+    SDMMC_FREQ_52M,
+    SDMMC_FREQ_HIGHSPEED,
+    SDMMC_FREQ_26M,
+    SDMMC_FREQ_DEFAULT,
+];
+
 pub struct SDCardPeripherals {
     pub slot: SDMMC1,
     pub cmd: Gpio35,
@@ -34,6 +46,60 @@ macro_rules! sd_peripherals {
     };
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusWidth {
+    One,
+    Four,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SDCardSettings {
+    pub bus_width: BusWidth,
+    pub frequency_khz: u32,
+    /// The dev board has external pullups, so they're off by default
+    pub internal_pullups: bool,
+}
+
+impl Default for SDCardSettings {
+    fn default() -> Self {
+        Self {
+            bus_width: BusWidth::Four,
+            frequency_khz: SDMMC_FREQ_52M,
+            internal_pullups: false,
+        }
+    }
+}
+
+impl SDCardSettings {
+    /// These settings first, then with slower frequencies, and then all of them again with the
+    /// 1-bit bus
+    fn fallbacks(&self) -> Vec<SDCardSettings> {
+        let frequencies: Vec<u32> = std::iter::once(self.frequency_khz)
+            .chain(
+                FALLBACK_FREQUENCIES_KHZ
+                    .iter()
+                    .copied()
+                    .filter(|frequency_khz| *frequency_khz < self.frequency_khz),
+            )
+            .collect();
+        let bus_widths = match self.bus_width {
+            BusWidth::Four => vec![BusWidth::Four, BusWidth::One],
+            BusWidth::One => vec![BusWidth::One],
+        };
+        let mut fallbacks = vec![];
+        for bus_width in bus_widths {
+            for &frequency_khz in &frequencies {
+                fallbacks.push(SDCardSettings {
+                    bus_width,
+                    frequency_khz,
+                    ..*self
+                });
+            }
+        }
+        fallbacks
+    }
+}
+
 pub enum SDCardState<'a> {
     Init,
     DriverInstalled {
@@ -46,16 +112,19 @@ pub enum SDCardState<'a> {
 }
 
 pub struct SDCardStorage<'a> {
+    peripherals: SDCardPeripherals,
     state: SDCardState<'a>,
 }
 
 impl<'a> SDCardStorage<'a> {
-    pub fn new() -> Self {
+    pub fn new(peripherals: SDCardPeripherals) -> Self {
         Self {
+            peripherals,
             state: SDCardState::Init,
         }
     }
-    pub fn install_driver(&mut self, peripherals: SDCardPeripherals) -> anyhow::Result<()> {
+
+    pub fn install_driver(&mut self, settings: &SDCardSettings) -> anyhow::Result<()> {
         match &self.state {
             SDCardState::DriverInstalled { .. } => {
                 bail!("Driver already installed");
@@ -66,32 +135,80 @@ impl<'a> SDCardStorage<'a> {
             _ => {}
         }
         let mut host_config = SdMmcHostConfiguration::new();
-        // Notice: the dev board use external pullups
-        // TODO: make this configurable?
-        host_config.enable_internal_pullups = false;
+        host_config.enable_internal_pullups = settings.internal_pullups;
         let mut card_config = SdCardConfiguration::new();
-        // TODO: make this config
-        card_config.speed_khz = SDMMC_FREQ_52M;
-        self.state = SDCardState::DriverInstalled {
-            driver: SdCardDriver::new_mmc(
-                SdMmcHostDriver::new_4bits(
-                    peripherals.slot,
-                    peripherals.cmd,
-                    peripherals.clk,
-                    peripherals.d0,
-                    peripherals.d1,
-                    peripherals.d2,
-                    peripherals.d3,
+        card_config.speed_khz = settings.frequency_khz;
+        let peripherals = &mut self.peripherals;
+        // Safety: the driver holding the peripherals is always dropped before installing
+        // another one, so they're never used twice
+        let host = unsafe {
+            match settings.bus_width {
+                BusWidth::One => SdMmcHostDriver::new_1bit(
+                    peripherals.slot.clone_unchecked(),
+                    peripherals.cmd.clone_unchecked(),
+                    peripherals.clk.clone_unchecked(),
+                    peripherals.d0.clone_unchecked(),
+                    None::<gpio::AnyIOPin>,
+                    None::<gpio::AnyIOPin>,
+                    &host_config,
+                )?,
+                BusWidth::Four => SdMmcHostDriver::new_4bits(
+                    peripherals.slot.clone_unchecked(),
+                    peripherals.cmd.clone_unchecked(),
+                    peripherals.clk.clone_unchecked(),
+                    peripherals.d0.clone_unchecked(),
+                    peripherals.d1.clone_unchecked(),
+                    peripherals.d2.clone_unchecked(),
+                    peripherals.d3.clone_unchecked(),
                     None::<gpio::AnyIOPin>,
                     None::<gpio::AnyIOPin>,
                     &host_config,
                 )?,
-                &card_config,
-            )?,
+            }
+        };
+        self.state = SDCardState::DriverInstalled {
+            driver: SdCardDriver::new_mmc(host, &card_config)?,
         };
         Ok(())
     }
 
+    /// Install the driver and mount the file system, stepping down to slower settings until the
+    /// card works. Returns the settings it works with.
+    pub fn mount_with_fallback(
+        &mut self,
+        settings: &SDCardSettings,
+        mount_path: &str,
+        max_fds: usize,
+    ) -> anyhow::Result<SDCardSettings> {
+        let mut last_error = None;
+        for candidate in settings.fallbacks() {
+            let result = self
+                .install_driver(&candidate)
+                .and_then(|_| self.mount(mount_path, max_fds));
+            match result {
+                Ok(()) if candidate == *settings => {
+                    log::info!("SD card mounted with {candidate:?}");
+                    return Ok(candidate);
+                }
+                Ok(()) => {
+                    log::warn!("SD card only works with {candidate:?} instead of {settings:?}");
+                    return Ok(candidate);
+                }
+                Err(error) => {
+                    log::warn!("Failed to mount SD card with {candidate:?}, error: {error:#}");
+                    self.unmount();
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No SD card settings to try")))
+    }
+
+    /// Unmount the file system and uninstall the driver, so that it can be installed again
+    pub fn unmount(&mut self) {
+        self.state = SDCardState::Init;
+    }
+
     pub fn mount(&mut self, mount_path: &str, max_fds: usize) -> anyhow::Result<()> {
         self.state = match replace(&mut self.state, SDCardState::Init) {
             SDCardState::Init => {
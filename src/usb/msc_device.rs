@@ -1,9 +1,22 @@
 use crate::storage::sd_card::SDCardStorage;
 use crate::storage::spiflash::SPIFlashStorage;
 use anyhow::Context;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
 use esp_idf_svc::handle::RawHandle;
-use esp_idf_svc::sys::{esp, tinyusb_config_t, tinyusb_driver_install, tinyusb_msc_event_t, tinyusb_msc_event_type_t, tinyusb_msc_event_type_t_TINYUSB_MSC_EVENT_MOUNT_CHANGED, tinyusb_msc_event_type_t_TINYUSB_MSC_EVENT_PREMOUNT_CHANGED, tinyusb_msc_sdmmc_config_t, tinyusb_msc_spiflash_config_t, tinyusb_msc_storage_init_sdmmc, tinyusb_msc_storage_init_spiflash};
+use esp_idf_svc::sys::{esp, tinyusb_config_t, tinyusb_driver_install, tinyusb_msc_event_t, tinyusb_msc_event_type_t, tinyusb_msc_event_type_t_TINYUSB_MSC_EVENT_MOUNT_CHANGED, tinyusb_msc_event_type_t_TINYUSB_MSC_EVENT_PREMOUNT_CHANGED, tinyusb_msc_sdmmc_config_t, tinyusb_msc_spiflash_config_t, tinyusb_msc_storage_init_sdmmc, tinyusb_msc_storage_init_spiflash, tinyusb_msc_storage_in_use_by_usb_host, tud_mounted, tud_speed_get, tusb_speed_t_TUSB_SPEED_FULL, tusb_speed_t_TUSB_SPEED_HIGH, tusb_speed_t_TUSB_SPEED_LOW, CONFIG_TINYUSB_MSC_BUFSIZE};
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const MOUNT_EVENT_QUEUE_SIZE: usize = 8;
+
+/// Read by the TinyUSB task through `tud_msc_is_writable_cb`
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Mount state changes reported by TinyUSB, `true` means mounted. The callback runs in the
+/// TinyUSB task, so we only queue them here and let the app consume them.
+pub static MOUNT_CHANGED_EVENTS: Channel<CriticalSectionRawMutex, bool, MOUNT_EVENT_QUEUE_SIZE> =
+    Channel::new();
 
 pub trait Storage {
     fn config_usb(&self) -> anyhow::Result<()>;
@@ -38,7 +51,7 @@ impl Storage for SDCardStorage<'_> {
 }
 #[derive(Debug, Default, Clone)]
 pub struct MSCDeviceConfig {
-    pub high_speed: bool,
+    pub read_only: bool,
 }
 
 pub struct MSCDevice {
@@ -55,11 +68,43 @@ fn msc_event_type_to_str(event_type: tinyusb_msc_event_type_t) -> String {
 }
 
 unsafe extern "C" fn storage_mount_changed_cb(event: *mut tinyusb_msc_event_t) {
+    let is_mounted = (*event).__bindgen_anon_1.mount_changed_data.is_mounted;
     log::info!(
         "Mount changed event, type={}, is_mounted={}",
         msc_event_type_to_str((*event).type_),
-        (*event).__bindgen_anon_1.mount_changed_data.is_mounted
+        is_mounted
     );
+    if (*event).type_ == tinyusb_msc_event_type_t_TINYUSB_MSC_EVENT_MOUNT_CHANGED
+        && MOUNT_CHANGED_EVENTS.try_send(is_mounted).is_err()
+    {
+        log::warn!("Mount changed event queue is full, dropping event");
+    }
+}
+
+/// Overrides the weak TinyUSB callback, so that the host sees a write-protected drive and its
+/// writes are rejected in read-only mode
+#[no_mangle]
+extern "C" fn tud_msc_is_writable_cb(_lun: u8) -> bool {
+    !READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Whether the volume is handed over to the USB host, in which case it's not mounted for us
+pub fn in_use_by_usb_host() -> bool {
+    unsafe { tinyusb_msc_storage_in_use_by_usb_host() }
+}
+
+/// The speed negotiated with the USB host, `None` if not connected to one
+pub fn usb_speed() -> Option<&'static str> {
+    if !unsafe { tud_mounted() } {
+        return None;
+    }
+    #[allow(non_upper_case_globals)]
+    match unsafe { tud_speed_get() } {
+        tusb_speed_t_TUSB_SPEED_LOW => Some("low"),
+        tusb_speed_t_TUSB_SPEED_FULL => Some("full"),
+        tusb_speed_t_TUSB_SPEED_HIGH => Some("high"),
+        _ => None,
+    }
 }
 
 impl MSCDevice {
@@ -71,16 +116,18 @@ impl MSCDevice {
     }
 
     pub fn install(&mut self) -> anyhow::Result<()> {
+        READ_ONLY.store(self.config.read_only, Ordering::Relaxed);
         self.storage.config_usb()?;
 
-        let mut tusb_cfg = tinyusb_config_t::default();
-        if self.config.high_speed {
-            // TODO:
-        }
+        let tusb_cfg = tinyusb_config_t::default();
         esp!(unsafe { tinyusb_driver_install(&tusb_cfg) })
             .with_context(|| "Failed to install TinyUSB driver")?;
 
-        log::info!("TinyUSB MSC driver installed.");
+        // The MSC buffer is allocated statically by TinyUSB, so its size is fixed at build time
+        log::info!(
+            "TinyUSB MSC driver installed, read_only={}, buffer_size={CONFIG_TINYUSB_MSC_BUFSIZE}",
+            self.config.read_only
+        );
         Ok(())
     }
 }
@@ -1 +1,4 @@
-pub mod session;
\ No newline at end of file
+pub mod link;
+pub mod session;
+pub mod status;
+pub mod supervisor;
@@ -0,0 +1,45 @@
+use esp_idf_svc::sys::{
+    esp, esp_wifi_sta_get_ap_info, esp_wifi_sta_get_negotiated_phymode, wifi_ap_record_t,
+    wifi_phy_mode_t, wifi_phy_mode_t_WIFI_PHY_MODE_11B, wifi_phy_mode_t_WIFI_PHY_MODE_11G,
+    wifi_phy_mode_t_WIFI_PHY_MODE_HE20, wifi_phy_mode_t_WIFI_PHY_MODE_HT20,
+    wifi_phy_mode_t_WIFI_PHY_MODE_HT40, wifi_phy_mode_t_WIFI_PHY_MODE_LR,
+};
+
+/// The quality of the link to the access point the station is connected to
+#[derive(Debug, Clone)]
+pub struct LinkQuality {
+    pub rssi: i8,
+    pub channel: u8,
+    /// The PHY mode negotiated with the access point, such as `11n HT20`. ESP-IDF doesn't expose
+    /// the actual PHY rate, so this is the closest thing to it.
+    pub phy_mode: Option<&'static str>,
+}
+
+fn phy_mode_name(phy_mode: wifi_phy_mode_t) -> Option<&'static str> {
+    #[allow(non_upper_case_globals)]
+    match phy_mode {
+        wifi_phy_mode_t_WIFI_PHY_MODE_LR => Some("LR"),
+        wifi_phy_mode_t_WIFI_PHY_MODE_11B => Some("11b"),
+        wifi_phy_mode_t_WIFI_PHY_MODE_11G => Some("11g"),
+        wifi_phy_mode_t_WIFI_PHY_MODE_HT20 => Some("11n HT20"),
+        wifi_phy_mode_t_WIFI_PHY_MODE_HT40 => Some("11n HT40"),
+        wifi_phy_mode_t_WIFI_PHY_MODE_HE20 => Some("11ax HE20"),
+        _ => None,
+    }
+}
+
+/// Read the link quality from the Wi-Fi driver, fails when the station is not connected
+pub fn link_quality() -> anyhow::Result<LinkQuality> {
+    let mut ap_info: wifi_ap_record_t = Default::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) })?;
+    let mut phy_mode: wifi_phy_mode_t = 0;
+    let phy_mode = match esp!(unsafe { esp_wifi_sta_get_negotiated_phymode(&mut phy_mode) }) {
+        Ok(_) => phy_mode_name(phy_mode),
+        Err(_) => None,
+    };
+    Ok(LinkQuality {
+        rssi: ap_info.rssi,
+        channel: ap_info.primary,
+        phy_mode,
+    })
+}
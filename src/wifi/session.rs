@@ -1,53 +1,302 @@
-use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use anyhow::{anyhow, bail};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_svc::wifi::{AccessPointInfo, AuthMethod, ClientConfiguration, Configuration};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::ipv4;
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::sys::EspError;
+use esp_idf_svc::sys::{
+    esp, esp_eap_client_clear_ca_cert, esp_eap_client_clear_certificate_and_key,
+    esp_eap_client_set_ca_cert, esp_eap_client_set_certificate_and_key,
+    esp_eap_client_set_identity, esp_eap_client_set_password, esp_eap_client_set_username,
+    esp_wifi_set_ps, esp_wifi_sta_enterprise_disable, esp_wifi_sta_enterprise_enable,
+    wifi_ps_type_t_WIFI_PS_MAX_MODEM, wifi_ps_type_t_WIFI_PS_MIN_MODEM, EspError,
+};
 use esp_idf_svc::timer::EspTaskTimerService;
-use esp_idf_svc::wifi::{AsyncWifi, EspWifi};
+use esp_idf_svc::wifi::{AsyncWifi, EspWifi, WifiDriver};
+use std::fmt::{Debug, Formatter};
+use std::net::Ipv4Addr;
+use std::ptr;
+use std::sync::Arc;
+
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+/// The modem power-save mode of the station
+#[derive(Debug, Clone, Copy)]
+pub enum PowerSave {
+    MinModem,
+    MaxModem,
+}
+
+/// WPA2-Enterprise credentials with the certificates and key already loaded. PEM data has to be
+/// NUL terminated. The EAP client keeps pointers to the certificate buffers instead of copying
+/// them, so this has to outlive the connection.
+#[derive(Default, Clone)]
+pub struct EapConfig {
+    pub identity: Option<String>,
+    pub anonymous_identity: Option<String>,
+    pub username: Option<String>,
+    pub ca_cert: Option<Vec<u8>>,
+    pub client_cert: Option<Vec<u8>>,
+    pub client_key: Option<Vec<u8>>,
+    pub client_key_password: Option<String>,
+}
+
+impl Debug for EapConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EapConfig")
+            .field("identity", &self.identity)
+            .field("anonymous_identity", &self.anonymous_identity)
+            .field("username", &self.username)
+            .field("ca_cert_len", &self.ca_cert.as_ref().map(|data| data.len()))
+            .field(
+                "client_cert_len",
+                &self.client_cert.as_ref().map(|data| data.len()),
+            )
+            .field(
+                "client_key_len",
+                &self.client_key.as_ref().map(|data| data.len()),
+            )
+            .finish()
+    }
+}
+
+impl EapConfig {
+    fn apply(&self, password: Option<&str>) -> anyhow::Result<()> {
+        let outer_identity = self
+            .anonymous_identity
+            .as_ref()
+            .or(self.identity.as_ref())
+            .ok_or_else(|| anyhow!("EAP identity is required"))?;
+        let username = self.username.as_ref().or(self.identity.as_ref());
+        unsafe {
+            esp!(esp_eap_client_set_identity(
+                outer_identity.as_ptr(),
+                outer_identity.len() as i32
+            ))?;
+            if let Some(username) = username {
+                esp!(esp_eap_client_set_username(
+                    username.as_ptr(),
+                    username.len() as i32
+                ))?;
+            }
+            if let Some(password) = password {
+                esp!(esp_eap_client_set_password(
+                    password.as_ptr(),
+                    password.len() as i32
+                ))?;
+            }
+            match &self.ca_cert {
+                Some(ca_cert) => esp!(esp_eap_client_set_ca_cert(
+                    ca_cert.as_ptr(),
+                    ca_cert.len() as i32
+                ))?,
+                None => esp_eap_client_clear_ca_cert(),
+            }
+            match (&self.client_cert, &self.client_key) {
+                (Some(client_cert), Some(client_key)) => {
+                    let (key_password, key_password_len) = match &self.client_key_password {
+                        Some(key_password) => (key_password.as_ptr(), key_password.len()),
+                        None => (ptr::null(), 0),
+                    };
+                    esp!(esp_eap_client_set_certificate_and_key(
+                        client_cert.as_ptr(),
+                        client_cert.len() as i32,
+                        client_key.as_ptr(),
+                        client_key.len() as i32,
+                        key_password,
+                        key_password_len as i32,
+                    ))?
+                }
+                (None, None) => esp_eap_client_clear_certificate_and_key(),
+                _ => bail!("Both client_cert and client_key are required for EAP-TLS"),
+            }
+            esp!(esp_wifi_sta_enterprise_enable())?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Default, Debug, Clone)]
 pub struct WifiConfig {
     pub ssid: String,
     pub password: Option<String>,
     pub auth_method: Option<AuthMethod>,
+    pub priority: i32,
+    /// Connect even when it's not in the scan results
+    pub hidden: bool,
+    pub eap: Option<EapConfig>,
+}
+
+/// IP settings of the station interface, DHCP is used unless `address` is set
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct IpConfig {
+    pub address: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub netmask: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub hostname: Option<String>,
 }
 
+impl IpConfig {
+    fn ipv4_configuration(&self) -> anyhow::Result<ipv4::Configuration> {
+        let Some(address) = self.address else {
+            return Ok(ipv4::Configuration::Client(
+                ipv4::ClientConfiguration::default(),
+            ));
+        };
+        let gateway = self
+            .gateway
+            .ok_or_else(|| anyhow!("Gateway is required with a static address"))?;
+        let netmask = u32::from(self.netmask.unwrap_or(DEFAULT_NETMASK));
+        if netmask.count_ones() != netmask.leading_ones() {
+            bail!("Invalid netmask {}", Ipv4Addr::from(netmask));
+        }
+        Ok(ipv4::Configuration::Client(
+            ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                ip: address,
+                subnet: ipv4::Subnet {
+                    gateway,
+                    mask: ipv4::Mask(netmask.leading_ones() as u8),
+                },
+                dns: self.dns.first().copied(),
+                secondary_dns: self.dns.get(1).copied(),
+            }),
+        ))
+    }
+}
+
+fn new_sta_netif(ip_config: &IpConfig) -> anyhow::Result<EspNetif> {
+    let mut sta_netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: Some(ip_config.ipv4_configuration()?),
+        ..NetifConfiguration::wifi_default_client()
+    })?;
+    if let Some(hostname) = &ip_config.hostname {
+        sta_netif.set_hostname(hostname)?;
+    }
+    Ok(sta_netif)
+}
+
+/// Pick the known network to connect to among the ones in range. Higher priority wins, and the
+/// stronger signal wins among the same priority. Hidden networks are never in the scan results,
+/// so they count as in range with the weakest signal.
+fn select_network<'c>(
+    configs: &'c [WifiConfig],
+    access_points: &[AccessPointInfo],
+) -> Option<&'c WifiConfig> {
+    configs
+        .iter()
+        .filter_map(|config| {
+            access_points
+                .iter()
+                .filter(|ap| ap.ssid.as_str() == config.ssid)
+                .map(|ap| ap.signal_strength)
+                .max()
+                .or(config.hidden.then_some(i8::MIN))
+                .map(|signal_strength| (config, signal_strength))
+        })
+        .max_by_key(|(config, signal_strength)| (config.priority, *signal_strength))
+        .map(|(config, _)| config)
+}
+
+/// The session shared between the supervisor keeping it connected and the API scanning for
+/// networks. The lock is held while connecting, so scans wait for it instead of interfering.
+pub type SharedWifiSession = Arc<Mutex<CriticalSectionRawMutex, WifiSession<'static>>>;
+
 pub struct WifiSession<'a> {
     async_wifi: AsyncWifi<EspWifi<'a>>,
+    configs: Vec<WifiConfig>,
+    ip_config: IpConfig,
+    current_ssid: Option<String>,
 }
 
 impl<'a> WifiSession<'a> {
-    pub(crate) fn new(config: &WifiConfig, modem: Modem) -> anyhow::Result<Self> {
+    pub(crate) fn new(
+        configs: &[WifiConfig],
+        ip_config: &IpConfig,
+        modem: Modem,
+        nvs: Option<EspDefaultNvsPartition>,
+    ) -> anyhow::Result<Self> {
         let sys_loop = EspSystemEventLoop::take()?;
         let timer_service = EspTaskTimerService::new()?;
-        let nvs = EspDefaultNvsPartition::take()?;
-        let mut async_wifi = AsyncWifi::wrap(
-            EspWifi::new(modem, sys_loop.clone(), Some(nvs))?,
+        let async_wifi = AsyncWifi::wrap(
+            EspWifi::wrap_all(
+                WifiDriver::new(modem, sys_loop.clone(), nvs)?,
+                new_sta_netif(ip_config)?,
+                EspNetif::new(NetifStack::Ap)?,
+            )?,
             sys_loop,
             timer_service,
         )?;
+        log::info!(
+            "Initialized wifi with {} networks, ip_config={ip_config:?}",
+            configs.len()
+        );
+        Ok(Self {
+            async_wifi,
+            configs: configs.to_vec(),
+            ip_config: ip_config.clone(),
+            current_ssid: None,
+        })
+    }
+
+    pub async fn connect(&mut self) -> anyhow::Result<()> {
+        if !self.async_wifi.is_started()? {
+            // The configuration has to be client mode before starting, so that we can scan
+            self.async_wifi
+                .set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+            self.async_wifi.start().await?;
+            log::info!("Wifi started");
+        }
+        if self.async_wifi.is_connected()? {
+            self.async_wifi.disconnect().await?;
+        }
+        self.current_ssid = None;
+
+        let access_points = self.async_wifi.scan().await?;
+        log::info!("Found {} access points", access_points.len());
+        // Borrow the config instead of cloning it, the EAP client refers to its certificates
+        let config = select_network(&self.configs, &access_points)
+            .ok_or_else(|| anyhow!("None of the configured networks is in range"))?;
+        log::info!(
+            "Selected network {}, priority={}",
+            config.ssid,
+            config.priority
+        );
 
         let mut client_config = ClientConfiguration {
-            ssid: config.ssid.as_str().try_into().unwrap(),
+            ssid: config
+                .ssid
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("SSID {} is longer than 32 bytes", config.ssid))?,
             ..Default::default()
         };
-        if let Some(password) = &config.password {
-            client_config.password = password.as_str().try_into().unwrap();
+        match &config.eap {
+            Some(_) => {
+                // The password goes to the EAP client instead for enterprise networks
+                client_config.auth_method =
+                    config.auth_method.unwrap_or(AuthMethod::WPA2Enterprise);
+            }
+            None => {
+                if let Some(password) = &config.password {
+                    client_config.password = password.as_str().try_into().map_err(|_| {
+                        anyhow!("Password of {} is longer than 64 bytes", config.ssid)
+                    })?;
+                }
+                if let Some(auth_method) = &config.auth_method {
+                    client_config.auth_method = *auth_method;
+                }
+            }
         }
-        if let Some(auth_method) = &config.auth_method {
-            client_config.auth_method = *auth_method;
+        self.async_wifi
+            .set_configuration(&Configuration::Client(client_config))?;
+        match &config.eap {
+            Some(eap) => eap.apply(config.password.as_deref())?,
+            None => esp!(unsafe { esp_wifi_sta_enterprise_disable() })?,
         }
-        let wifi_configuration: Configuration = Configuration::Client(client_config);
-        async_wifi.set_configuration(&wifi_configuration)?;
-        log::info!("Initialized wifi");
-        Ok(Self { async_wifi })
-    }
-
-    pub async fn connect(&mut self) -> anyhow::Result<()> {
-        self.async_wifi.start().await?;
-        log::info!("Wifi started");
 
         self.async_wifi.connect().await?;
         log::info!("Wifi connected");
@@ -55,9 +304,98 @@ impl<'a> WifiSession<'a> {
         self.async_wifi.wait_netif_up().await?;
         log::info!("Wifi netif up");
 
+        // With DHCP, the configured DNS servers replace the ones from the lease
+        if self.ip_config.address.is_none() {
+            let sta_netif = self.async_wifi.wifi_mut().sta_netif_mut();
+            if let Some(dns) = self.ip_config.dns.first() {
+                sta_netif.set_dns(*dns);
+            }
+            if let Some(dns) = self.ip_config.dns.get(1) {
+                sta_netif.set_secondary_dns(*dns);
+            }
+        }
+
+        self.current_ssid = Some(config.ssid.clone());
+        Ok(())
+    }
+
+    /// Replace the networks and the IP settings. The station is disconnected first, so that the
+    /// supervisor connects it again with the new ones. A new IP config needs a new interface,
+    /// which is swapped in with the radio stopped.
+    pub async fn reconfigure(
+        &mut self,
+        configs: &[WifiConfig],
+        ip_config: &IpConfig,
+    ) -> anyhow::Result<()> {
+        // Fail before touching the connection if the new IP config is invalid
+        let sta_netif = if ip_config != &self.ip_config {
+            Some(new_sta_netif(ip_config)?)
+        } else {
+            None
+        };
+        if self.async_wifi.is_connected()? {
+            self.async_wifi.disconnect().await?;
+        }
+        self.current_ssid = None;
+        if let Some(sta_netif) = sta_netif {
+            self.stop().await?;
+            self.async_wifi.wifi_mut().swap_netif_sta(sta_netif)?;
+            self.ip_config = ip_config.clone();
+        }
+        self.configs = configs.to_vec();
+        log::info!(
+            "Reconfigured wifi with {} networks, ip_config={ip_config:?}",
+            configs.len()
+        );
+        Ok(())
+    }
+
+    /// Run an active scan. The station stays connected while the radio visits the other
+    /// channels.
+    pub async fn scan(&mut self) -> anyhow::Result<Vec<AccessPointInfo>> {
+        if !self.async_wifi.is_started()? {
+            bail!("Wifi is not started yet");
+        }
+        Ok(self.async_wifi.scan().await?)
+    }
+
+    /// Stop the radio altogether, `connect` starts it again
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        if self.async_wifi.is_started()? {
+            self.async_wifi.stop().await?;
+            log::info!("Wifi stopped");
+        }
+        self.current_ssid = None;
         Ok(())
     }
 
+    pub fn set_power_save(&mut self, power_save: PowerSave) -> Result<(), EspError> {
+        let ps_type = match power_save {
+            PowerSave::MinModem => wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+            PowerSave::MaxModem => wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+        };
+        esp!(unsafe { esp_wifi_set_ps(ps_type) })?;
+        log::info!("Wifi power save set to {power_save:?}");
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> Result<bool, EspError> {
+        self.async_wifi.is_connected()
+    }
+
+    pub fn current_ssid(&self) -> Option<&str> {
+        self.current_ssid.as_deref()
+    }
+
+    pub fn hostname(&self) -> Option<String> {
+        self.async_wifi
+            .wifi()
+            .sta_netif()
+            .get_hostname()
+            .ok()
+            .map(|hostname| hostname.to_string())
+    }
+
     pub fn get_ip_info(&self) -> Result<ipv4::IpInfo, EspError> {
         self.async_wifi.wifi().sta_netif().get_ip_info()
     }
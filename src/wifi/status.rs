@@ -0,0 +1,33 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// A snapshot of the Wi-Fi connection, shared with the parts of the firmware which can't hold the
+/// `WifiSession` itself, such as the HTTP server running in its own task.
+#[derive(Debug, Default, Clone)]
+pub struct WifiStatus {
+    pub ssid: Option<String>,
+    pub ip: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub netmask: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub hostname: Option<String>,
+    /// When the current connection was established
+    pub connected_at: Option<Instant>,
+    /// The state last told to the API layer, which may be `Disconnected` while Wi-Fi itself is
+    /// still connected, like outside the scheduled windows
+    pub state: WifiState,
+}
+
+pub type SharedWifiStatus = Arc<RwLock<WifiStatus>>;
+
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub enum WifiState {
+    Connected,
+    #[default]
+    Disconnected,
+}
+
+pub type WifiStateSignal = Signal<CriticalSectionRawMutex, WifiState>;
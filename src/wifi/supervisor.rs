@@ -0,0 +1,240 @@
+use crate::schedule::{IdleMode, Schedule};
+use crate::wifi::session::{PowerSave, SharedWifiSession};
+use crate::wifi::status::{SharedWifiStatus, WifiState, WifiStateSignal, WifiStatus};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use esp_idf_svc::eventloop::{EspSubscription, EspSystemEventLoop, System};
+use esp_idf_svc::timer::EspTaskTimerService;
+use esp_idf_svc::wifi::WifiEvent;
+use futures::future::{select, Either};
+use std::net::Ipv4Addr;
+use std::pin::pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/// How often to check whether we've entered or left a scheduled window
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Signaled after the shared `WifiSession` is reconfigured, so that the supervisor reconnects
+/// right away instead of waiting out its backoff
+pub type WifiReloadSignal = Signal<CriticalSectionRawMutex, ()>;
+
+/// Keeps the shared `WifiSession` connected. It listens to the station connect and
+/// disconnect events from the system event loop, reconnects with exponential backoff and
+/// tells the API layer about it via the state signal. With a schedule, Wi-Fi is put into the
+/// idle mode outside the scheduled windows, and the API layer is told it's disconnected.
+pub struct WifiSupervisor {
+    wifi: SharedWifiSession,
+    status: SharedWifiStatus,
+    state_signal: Rc<WifiStateSignal>,
+    disconnected: Arc<Signal<CriticalSectionRawMutex, ()>>,
+    reload: Rc<WifiReloadSignal>,
+    schedule: Option<Schedule>,
+    _subscription: EspSubscription<'static, System>,
+}
+
+impl WifiSupervisor {
+    pub fn new(
+        wifi: SharedWifiSession,
+        status: SharedWifiStatus,
+        state_signal: Rc<WifiStateSignal>,
+        reload: Rc<WifiReloadSignal>,
+        schedule: Option<Schedule>,
+    ) -> anyhow::Result<Self> {
+        let disconnected = Arc::new(Signal::new());
+        let captured_disconnected = disconnected.clone();
+        let subscription =
+            EspSystemEventLoop::take()?.subscribe::<WifiEvent, _>(move |event| match event {
+                WifiEvent::StaConnected(_) => {
+                    log::info!("Wifi station connected event");
+                }
+                WifiEvent::StaDisconnected(_) => {
+                    log::info!("Wifi station disconnected event");
+                    captured_disconnected.signal(());
+                }
+                _ => {}
+            })?;
+        Ok(Self {
+            wifi,
+            status,
+            state_signal,
+            disconnected,
+            reload,
+            schedule,
+            _subscription: subscription,
+        })
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        let mut wifi = self.wifi.lock().await;
+        wifi.connect().await?;
+        let ip_info = wifi.get_ip_info()?;
+        log::info!("Connected wifi: {:#?}", ip_info);
+        let prefix_len = ip_info.subnet.mask.0 as u32;
+        *self.status.write().unwrap() = WifiStatus {
+            ssid: wifi.current_ssid().map(|ssid| ssid.to_string()),
+            ip: Some(ip_info.ip),
+            gateway: Some(ip_info.subnet.gateway),
+            netmask: Some(Ipv4Addr::from(
+                u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0),
+            )),
+            dns: ip_info
+                .dns
+                .into_iter()
+                .chain(ip_info.secondary_dns)
+                .filter(|dns| !dns.is_unspecified())
+                .collect(),
+            hostname: wifi.hostname(),
+            connected_at: Some(Instant::now()),
+            state: WifiState::Disconnected,
+        };
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> bool {
+        self.wifi.lock().await.is_connected().unwrap_or(false)
+    }
+
+    fn set_state(&self, state: WifiState) {
+        self.status.write().unwrap().state = state;
+        self.state_signal.signal(state);
+    }
+
+    fn is_scheduled(&self) -> bool {
+        self.schedule
+            .as_ref()
+            .map(|schedule| schedule.is_active(OffsetDateTime::now_utc()))
+            .unwrap_or(true)
+    }
+
+    async fn enter_idle(&mut self) -> anyhow::Result<()> {
+        let Some(schedule) = &self.schedule else {
+            return Ok(());
+        };
+        let idle_mode = schedule.idle_mode;
+        log::info!("Outside of the scheduled windows, going idle with {idle_mode:?}");
+        self.set_state(WifiState::Disconnected);
+        let mut wifi = self.wifi.lock().await;
+        match idle_mode {
+            IdleMode::WifiOff => {
+                wifi.stop().await?;
+                *self.status.write().unwrap() = WifiStatus::default();
+            }
+            IdleMode::PowerSave(power_save) => wifi.set_power_save(power_save)?,
+        }
+        Ok(())
+    }
+
+    async fn leave_idle(&mut self) -> anyhow::Result<()> {
+        log::info!("Entered a scheduled window, waking up");
+        let is_connected = {
+            let mut wifi = self.wifi.lock().await;
+            // Back to the ESP-IDF default
+            wifi.set_power_save(PowerSave::MinModem)?;
+            wifi.is_connected().unwrap_or(false)
+        };
+        // Still connected if it was in power-save mode, otherwise the loop connects it
+        if is_connected {
+            self.set_state(WifiState::Connected);
+        }
+        Ok(())
+    }
+
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let timer_service = EspTaskTimerService::new()?;
+        let mut timer = timer_service.timer_async()?;
+        let mut backoff = MIN_BACKOFF;
+        let mut is_idle = false;
+        loop {
+            if !self.is_scheduled() {
+                if !is_idle {
+                    self.enter_idle().await?;
+                    is_idle = true;
+                }
+                timer.after(SCHEDULE_CHECK_INTERVAL).await?;
+                continue;
+            }
+            if is_idle {
+                self.leave_idle().await?;
+                is_idle = false;
+            }
+
+            if !self.is_connected().await {
+                if let Err(error) = self.connect().await {
+                    log::warn!(
+                        "Failed to connect wifi with error: {error:#}, retry in {} secs",
+                        backoff.as_secs()
+                    );
+                    let reload = self.reload.clone();
+                    match select(pin!(reload.wait()), pin!(timer.after(backoff))).await {
+                        Either::Left(_) => {
+                            log::info!("Wifi config changed, retry now");
+                            backoff = MIN_BACKOFF;
+                        }
+                        Either::Right((result, _)) => {
+                            result?;
+                            backoff = (backoff * 2).min(MAX_BACKOFF);
+                        }
+                    }
+                    continue;
+                }
+                backoff = MIN_BACKOFF;
+                // Disconnect events from our own attempts to connect are not interesting, and
+                // neither is a config change made before connecting
+                self.disconnected.reset();
+                self.reload.reset();
+                self.set_state(WifiState::Connected);
+            }
+
+            let disconnected = self.disconnected.clone();
+            let reload = self.reload.clone();
+            match select(
+                pin!(disconnected.wait()),
+                select(
+                    pin!(reload.wait()),
+                    pin!(timer.after(SCHEDULE_CHECK_INTERVAL)),
+                ),
+            )
+            .await
+            {
+                Either::Left(_) => {
+                    if self.is_connected().await {
+                        continue;
+                    }
+                    log::warn!("Wifi connection lost, reconnecting");
+                }
+                Either::Right((Either::Left(_), _)) => {
+                    // The session disconnected itself for the new config
+                    log::info!("Wifi config changed, reconnecting");
+                }
+                Either::Right((Either::Right((result, _)), _)) => {
+                    result?;
+                    continue;
+                }
+            }
+            *self.status.write().unwrap() = WifiStatus::default();
+            self.set_state(WifiState::Disconnected);
+        }
+    }
+
+    /// Keep it running. Whenever it fails, it's restarted after a short delay, so that the rest of
+    /// the firmware keeps working without Wi-Fi in the meantime.
+    pub async fn supervise(&mut self) -> anyhow::Result<()> {
+        let timer_service = EspTaskTimerService::new()?;
+        let mut timer = timer_service.timer_async()?;
+        loop {
+            if let Err(error) = self.run().await {
+                log::error!(
+                    "Wifi supervisor stopped with error: {error:#}, restart in {} secs",
+                    RESTART_DELAY.as_secs()
+                );
+            }
+            timer.after(RESTART_DELAY).await?;
+        }
+    }
+}